@@ -11,8 +11,12 @@ trap "kill 0" EXIT
 
 TransportSecurityProtocols=( noise plaintext )
 
+echo "# Generate Rust server identity."
+RustServerPeerId=$(./rust/target/release/keygen --output rust/identity.key --force)
+
+echo
 echo "# Start Rust and Golang servers."
-./rust/target/release/server --private-key-pkcs8 rust/test.pk8 --listen-address /ip4/0.0.0.0/tcp/9992 > /dev/null 2>&1 &
+./rust/target/release/server --identity rust/identity.key --listen-address /ip4/0.0.0.0/tcp/9992 > /dev/null 2>&1 &
 ./golang/go-libp2p-perf --fake-crypto-seed --listen-address /ip4/0.0.0.0/tcp/9993 --transport-security noise > /dev/null 2>&1 &
 ./golang/go-libp2p-perf --fake-crypto-seed --listen-address /ip4/0.0.0.0/tcp/9994 --transport-security plaintext > /dev/null 2>&1 &
 
@@ -20,12 +24,8 @@ sleep 1
 
 echo
 echo "# Rust -> Rust"
-for Protocol in ${TransportSecurityProtocols[*]}
-do
-    echo
-    echo "## Transport security $Protocol"
-    ./rust/target/release/client --server-address /ip4/127.0.0.1/tcp/9992 --transport-security $Protocol
-done
+echo
+./rust/target/release/sweep --server-address /ip4/127.0.0.1/tcp/9992 --transport-security noise,plaintext --yamux-receive-window 16777216 --buffer-size 128000
 
 echo
 echo "# Rust -> Golang"
@@ -42,7 +42,7 @@ for Protocol in ${TransportSecurityProtocols[*]}
 do
     echo
     echo "## Transport security $Protocol"
-    ./golang/go-libp2p-perf --server-address /ip4/127.0.0.1/tcp/9992/p2p/Qmcqq9TFaYbb94uwdER1BXyGfCFY4Bb1gKozxNyVvLvTSw --transport-security $Protocol
+    ./golang/go-libp2p-perf --server-address /ip4/127.0.0.1/tcp/9992/p2p/$RustServerPeerId --transport-security $Protocol
 done
 
 echo
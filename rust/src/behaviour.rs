@@ -1,19 +1,159 @@
-use crate::handler::{PerfHandler, PerfHandlerIn, PerfHandlerOut};
+use crate::clock::{Clock, SystemClock};
+use crate::handler::{
+    PerfHandler, PerfHandlerIn, PerfHandlerOut, PerfHandlerProto, Role, RunDone, RunStats,
+};
+use crate::limit::{RejectReason, RunLimiter};
+use crate::payload::Payload;
+use crate::resource::{CpuUsage, MemoryUsage};
 use libp2p::{
     core::{connection::ConnectionId, transport::ListenerId, ConnectedPoint},
     swarm::{
-        ConnectionHandler, DialError, IntoConnectionHandler, NetworkBehaviour,
-        NetworkBehaviourAction, NotifyHandler, PollParameters,
+        dial_opts::DialOpts, CloseConnection, ConnectionHandler, DialError, IntoConnectionHandler,
+        NetworkBehaviour, NetworkBehaviourAction, NotifyHandler, PollParameters,
     },
     Multiaddr, PeerId,
 };
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::fmt;
+use std::io;
+use std::sync::Arc;
 use std::task::{Context, Poll};
-use std::time::Duration;
+use std::time::{Duration, SystemTime};
+
+/// Configuration of the [`Perf`] behaviour.
+#[derive(Debug, Clone)]
+pub struct PerfConfig {
+    /// Size of the buffer written by the sender, respectively read into by the
+    /// receiver, per `poll_write` / `poll_read` call.
+    pub buffer_size: usize,
+    /// Duration the sender keeps sending before closing the substream.
+    pub run_duration: Duration,
+    /// Data written by the sender.
+    pub payload: Payload,
+    /// Clock used to time perf runs.
+    pub clock: Arc<dyn Clock>,
+    /// Start a perf run on each connection established by dialing.
+    ///
+    /// Disable when composing [`Perf`] with other behaviours, so that only
+    /// runs requested via [`Perf::start_run`] are executed.
+    pub auto_start: bool,
+    /// Only receive perf runs started by remote peers, never send data.
+    ///
+    /// Implies no automatic perf runs. Runs requested via [`Perf::start_run`]
+    /// fail right away.
+    pub passive: bool,
+    /// Maximum number of concurrent perf runs started by remote peers. Further
+    /// runs are rejected.
+    pub max_inbound_runs: Option<usize>,
+    /// Maximum number of concurrent perf runs started by a single remote peer.
+    /// Further runs from the peer are rejected.
+    pub max_inbound_runs_per_peer: Option<usize>,
+    /// Peers allowed to start perf runs. Anyone if `None`.
+    ///
+    /// Perf substreams from other peers are reset right after negotiation,
+    /// and inbound connections from them are closed.
+    pub allowed_peers: Option<HashSet<PeerId>>,
+    /// Maximum duration of a perf run started by a remote peer, measured from
+    /// the first byte received. Longer runs are aborted.
+    pub max_inbound_run_duration: Option<Duration>,
+    /// Maximum number of bytes received in a perf run started by a remote
+    /// peer. Runs sending more are aborted.
+    pub max_inbound_run_bytes: Option<usize>,
+    /// Abort a perf run started by a remote peer once no data was received
+    /// for the given duration.
+    pub inbound_idle_timeout: Option<Duration>,
+    /// Duration a connection is kept alive once no perf run is pending on it,
+    /// e.g. to start another run. Other behaviours may keep it alive longer.
+    pub connection_idle_timeout: Duration,
+}
+
+impl PerfConfig {
+    pub(crate) fn is_allowed(&self, peer_id: &PeerId) -> bool {
+        match &self.allowed_peers {
+            Some(allowed_peers) => allowed_peers.contains(peer_id),
+            None => true,
+        }
+    }
+}
+
+impl Default for PerfConfig {
+    fn default() -> Self {
+        PerfConfig {
+            // iPerf works by writing an array of len bytes a number of times.
+            // Default is 128 KB for TCP, 8 KB for UDP.
+            //
+            // https://iperf.fr/iperf-doc.php
+            buffer_size: 128_000,
+            run_duration: Duration::from_secs(10),
+            payload: Payload::Zeros,
+            clock: Arc::new(SystemClock),
+            auto_start: true,
+            passive: false,
+            max_inbound_runs: None,
+            max_inbound_runs_per_peer: None,
+            allowed_peers: None,
+            max_inbound_run_duration: None,
+            max_inbound_run_bytes: None,
+            inbound_idle_timeout: None,
+            connection_idle_timeout: Duration::from_secs(10),
+        }
+    }
+}
+
+/// Identifier of a perf run started by the local node.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct RunId(u64);
+
+impl fmt::Display for RunId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+/// Parameters of a single perf run, see [`Perf::start_run`].
+#[derive(Debug, Clone)]
+pub struct RunParams {
+    /// See [`PerfConfig::buffer_size`].
+    pub buffer_size: usize,
+    /// See [`PerfConfig::run_duration`].
+    pub run_duration: Duration,
+    /// See [`PerfConfig::payload`].
+    pub payload: Payload,
+}
+
+impl Default for RunParams {
+    fn default() -> Self {
+        RunParams::from(&PerfConfig::default())
+    }
+}
+
+impl From<&PerfConfig> for RunParams {
+    fn from(config: &PerfConfig) -> Self {
+        RunParams {
+            buffer_size: config.buffer_size,
+            run_duration: config.run_duration,
+            payload: config.payload.clone(),
+        }
+    }
+}
 
 #[derive(Default)]
 pub struct Perf {
-    outbox: Vec<
+    config: PerfConfig,
+    limiter: Arc<RunLimiter>,
+    next_run_id: u64,
+    /// Perf runs started by us and not yet reported, with the connection they
+    /// are running on.
+    pending_runs: HashMap<RunId, ConnectionId>,
+    /// Perf runs waiting for a connection to the given peer to be established.
+    queued_runs: HashMap<PeerId, Vec<(RunId, RunParams)>>,
+    connections: HashMap<PeerId, Vec<ConnectionId>>,
+    /// Remote address of each established connection.
+    remote_addresses: HashMap<ConnectionId, Multiaddr>,
+    /// Known addresses per peer, returned to the swarm when dialing a peer by
+    /// its [`PeerId`].
+    addresses: HashMap<PeerId, Vec<Multiaddr>>,
+    outbox: VecDeque<
         NetworkBehaviourAction<
             <Self as NetworkBehaviour>::OutEvent,
             <Self as NetworkBehaviour>::ConnectionHandler,
@@ -21,56 +161,339 @@ pub struct Perf {
     >,
 }
 
+impl Perf {
+    pub fn new(config: PerfConfig) -> Self {
+        Perf {
+            limiter: Arc::new(RunLimiter::new(
+                config.max_inbound_runs,
+                config.max_inbound_runs_per_peer,
+            )),
+            config,
+            ..Default::default()
+        }
+    }
+
+    /// Starts a perf run sending data to the given peer, dialing it if not
+    /// connected yet.
+    ///
+    /// The result of the run is reported via a [`PerfEvent`] tagged with the
+    /// returned [`RunId`].
+    pub fn start_run(&mut self, peer_id: PeerId, params: RunParams) -> RunId {
+        let id = self.next_run_id();
+        let _span = tracing::debug_span!("start_run", peer = %peer_id, run = %id).entered();
+
+        if self.config.passive {
+            tracing::debug!("Refusing to start perf run in passive mode.");
+            self.outbox.push_back(NetworkBehaviourAction::GenerateEvent(
+                PerfEvent::PerfRunFailed {
+                    id: Some(id),
+                    peer_id,
+                    error: io::Error::other("Perf runs are disabled in passive mode."),
+                },
+            ));
+            return id;
+        }
+
+        match self
+            .connections
+            .get(&peer_id)
+            .and_then(|connections| connections.first())
+        {
+            Some(connection) => {
+                let connection = *connection;
+                tracing::debug!(?connection, "Starting perf run on existing connection.");
+                self.start_run_on(peer_id, connection, id, params);
+            }
+            None => {
+                // Only dial once, the queued runs are started as soon as any
+                // connection to the peer is established.
+                if !self.queued_runs.contains_key(&peer_id) {
+                    tracing::debug!("Dialing peer to start perf run.");
+                    let handler = self.new_handler();
+                    self.outbox.push_back(NetworkBehaviourAction::Dial {
+                        opts: DialOpts::peer_id(peer_id).build(),
+                        handler,
+                    });
+                }
+                self.queued_runs
+                    .entry(peer_id)
+                    .or_default()
+                    .push((id, params));
+            }
+        }
+
+        id
+    }
+
+    /// Adds an address of the given peer, used when dialing the peer to start
+    /// a perf run.
+    ///
+    /// Addresses of peers successfully dialed are added automatically.
+    pub fn add_address(&mut self, peer_id: &PeerId, address: Multiaddr) {
+        let addresses = self.addresses.entry(*peer_id).or_default();
+        if !addresses.contains(&address) {
+            addresses.push(address);
+        }
+    }
+
+    /// Removes an address of the given peer. Returns whether the address was
+    /// known.
+    pub fn remove_address(&mut self, peer_id: &PeerId, address: &Multiaddr) -> bool {
+        let addresses = match self.addresses.get_mut(peer_id) {
+            Some(addresses) => addresses,
+            None => return false,
+        };
+
+        let len = addresses.len();
+        addresses.retain(|a| a != address);
+        let removed = addresses.len() != len;
+
+        if addresses.is_empty() {
+            self.addresses.remove(peer_id);
+        }
+
+        removed
+    }
+
+    /// Removes all addresses of the given peer.
+    pub fn remove_peer(&mut self, peer_id: &PeerId) {
+        self.addresses.remove(peer_id);
+    }
+
+    /// Returns the known addresses of the given peer.
+    pub fn addresses(&self, peer_id: &PeerId) -> &[Multiaddr] {
+        self.addresses
+            .get(peer_id)
+            .map(|addresses| addresses.as_slice())
+            .unwrap_or_default()
+    }
+
+    /// Number of perf runs started by remote peers currently in progress.
+    pub fn inbound_runs(&self) -> usize {
+        self.limiter.runs()
+    }
+
+    fn next_run_id(&mut self) -> RunId {
+        let id = RunId(self.next_run_id);
+        self.next_run_id += 1;
+        id
+    }
+
+    fn start_run_on(
+        &mut self,
+        peer_id: PeerId,
+        connection: ConnectionId,
+        id: RunId,
+        params: RunParams,
+    ) {
+        self.pending_runs.insert(id, connection);
+        self.outbox
+            .push_back(NetworkBehaviourAction::NotifyHandler {
+                peer_id,
+                event: PerfHandlerIn::StartPerf(id, params),
+                handler: NotifyHandler::One(connection),
+            })
+    }
+}
+
 impl NetworkBehaviour for Perf {
-    type ConnectionHandler = PerfHandler;
+    type ConnectionHandler = PerfHandlerProto;
 
     type OutEvent = PerfEvent;
 
     fn new_handler(&mut self) -> Self::ConnectionHandler {
-        PerfHandler::default()
+        PerfHandlerProto::new(self.config.clone(), self.limiter.clone())
     }
 
-    fn addresses_of_peer(&mut self, _peer_id: &PeerId) -> Vec<Multiaddr> {
-        vec![]
+    fn addresses_of_peer(&mut self, peer_id: &PeerId) -> Vec<Multiaddr> {
+        self.addresses.get(peer_id).cloned().unwrap_or_default()
     }
 
     fn inject_connection_established(
         &mut self,
         peer_id: &PeerId,
-        _: &ConnectionId,
+        connection: &ConnectionId,
         connected_point: &ConnectedPoint,
         _failed_addresses: Option<&Vec<Multiaddr>>,
         _other_established: usize,
     ) {
-        if connected_point.is_dialer() {
-            self.outbox.push(NetworkBehaviourAction::NotifyHandler {
-                peer_id: *peer_id,
-                event: PerfHandlerIn::StartPerf,
-                handler: NotifyHandler::Any,
-            })
-        };
+        if connected_point.is_listener() && !self.config.is_allowed(peer_id) {
+            tracing::info!(peer = %peer_id, "Closing connection from peer not allowed.");
+            self.outbox
+                .push_back(NetworkBehaviourAction::CloseConnection {
+                    peer_id: *peer_id,
+                    connection: CloseConnection::One(*connection),
+                });
+            self.outbox.push_back(NetworkBehaviourAction::GenerateEvent(
+                PerfEvent::PeerNotAllowed {
+                    peer_id: *peer_id,
+                    connection: *connection,
+                },
+            ));
+            // Neither run nor report perf runs on the closing connection.
+            return;
+        }
+
+        self.connections
+            .entry(*peer_id)
+            .or_default()
+            .push(*connection);
+        self.remote_addresses
+            .insert(*connection, connected_point.get_remote_address().clone());
+
+        // Remember addresses we managed to dial, e.g. to re-dial the peer for
+        // later perf runs. The remote address of a listener is not
+        // necessarily dialable.
+        if let ConnectedPoint::Dialer { address, .. } = connected_point {
+            self.add_address(peer_id, address.clone());
+        }
+
+        // Connections dialed via `Perf::start_run` only run the requested perf
+        // runs.
+        if let Some(queued) = self.queued_runs.remove(peer_id) {
+            for (id, params) in queued {
+                self.start_run_on(*peer_id, *connection, id, params);
+            }
+        } else if connected_point.is_dialer() && self.config.auto_start && !self.config.passive {
+            let id = self.next_run_id();
+            let params = RunParams::from(&self.config);
+            self.start_run_on(*peer_id, *connection, id, params);
+        }
+    }
+
+    fn inject_connection_closed(
+        &mut self,
+        peer_id: &PeerId,
+        connection: &ConnectionId,
+        _endpoint: &ConnectedPoint,
+        _handler: PerfHandler,
+        _remaining_established: usize,
+    ) {
+        if let Some(connections) = self.connections.get_mut(peer_id) {
+            connections.retain(|c| c != connection);
+            if connections.is_empty() {
+                self.connections.remove(peer_id);
+            }
+        }
+        self.remote_addresses.remove(connection);
+
+        // Runs still pending were dropped together with the connection.
+        let mut failed = self
+            .pending_runs
+            .iter()
+            .filter(|(_, c)| *c == connection)
+            .map(|(id, _)| *id)
+            .collect::<Vec<_>>();
+        failed.sort();
+        for id in failed {
+            tracing::debug!(peer = %peer_id, run = %id, "Connection closed during perf run.");
+            self.pending_runs.remove(&id);
+            self.outbox.push_back(NetworkBehaviourAction::GenerateEvent(
+                PerfEvent::PerfRunFailed {
+                    id: Some(id),
+                    peer_id: *peer_id,
+                    error: io::Error::new(
+                        io::ErrorKind::ConnectionAborted,
+                        "Connection closed during perf run.",
+                    ),
+                },
+            ));
+        }
     }
 
     fn inject_event(
         &mut self,
-        _peer_id: PeerId,
-        _connection: ConnectionId,
+        peer_id: PeerId,
+        connection: ConnectionId,
         event: <<Self::ConnectionHandler as IntoConnectionHandler>::Handler as ConnectionHandler>::OutEvent,
     ) {
+        let _span = tracing::debug_span!("perf", peer = %peer_id, ?connection).entered();
+
         match event {
-            PerfHandlerOut::PerfRunDone(duration, transfered) => self.outbox.push(
-                NetworkBehaviourAction::GenerateEvent(PerfEvent::PerfRunDone(duration, transfered)),
-            ),
+            PerfHandlerOut::PerfRunDone(done) => {
+                let RunDone {
+                    info,
+                    stats,
+                    finished_at,
+                    cpu,
+                    memory,
+                } = *done;
+                if let Some(id) = info.id {
+                    self.pending_runs.remove(&id);
+                }
+                let (sent, received) = match info.role {
+                    Role::Sender => (stats.transfered, 0),
+                    Role::Receiver => (0, stats.transfered),
+                };
+                let result = RunResult {
+                    id: info.id,
+                    peer_id,
+                    connection,
+                    remote_address: self
+                        .remote_addresses
+                        .get(&connection)
+                        .cloned()
+                        .expect("Event from established connection."),
+                    role: info.role,
+                    protocol: info.protocol,
+                    params: info.params,
+                    sent,
+                    received,
+                    started_at: info.started_at,
+                    finished_at,
+                    stats,
+                    cpu,
+                    memory,
+                };
+                self.outbox.push_back(NetworkBehaviourAction::GenerateEvent(
+                    PerfEvent::PerfRunDone(Box::new(result)),
+                ))
+            }
+            PerfHandlerOut::PerfRunFailed { id, error } => {
+                if let Some(id) = id {
+                    self.pending_runs.remove(&id);
+                }
+                self.outbox.push_back(NetworkBehaviourAction::GenerateEvent(
+                    PerfEvent::PerfRunFailed { id, peer_id, error },
+                ))
+            }
+            PerfHandlerOut::InboundRunRejected(reason) => {
+                tracing::debug!(%reason, "Rejected perf run.");
+                self.outbox.push_back(NetworkBehaviourAction::GenerateEvent(
+                    PerfEvent::InboundRunRejected {
+                        peer_id,
+                        connection,
+                        reason,
+                    },
+                ))
+            }
         }
     }
 
     fn inject_dial_failure(
         &mut self,
-        _peer_id: Option<PeerId>,
-        _handler: PerfHandler,
-        _error: &DialError,
+        peer_id: Option<PeerId>,
+        _handler: PerfHandlerProto,
+        error: &DialError,
     ) {
-        panic!("inject dial failure");
+        tracing::debug!(peer = ?peer_id, %error, "Failed to dial peer.");
+
+        // A connection might still be established by another dial.
+        if let Some(peer_id) = peer_id {
+            if self.connections.contains_key(&peer_id) {
+                return;
+            }
+
+            for (id, _) in self.queued_runs.remove(&peer_id).unwrap_or_default() {
+                self.outbox.push_back(NetworkBehaviourAction::GenerateEvent(
+                    PerfEvent::PerfRunFailed {
+                        id: Some(id),
+                        peer_id,
+                        error: io::Error::new(io::ErrorKind::NotConnected, error.to_string()),
+                    },
+                ));
+            }
+        }
     }
 
     fn inject_new_listen_addr(&mut self, _: ListenerId, _addr: &Multiaddr) {}
@@ -79,12 +502,12 @@ impl NetworkBehaviour for Perf {
 
     fn inject_new_external_addr(&mut self, _addr: &Multiaddr) {}
 
-    fn inject_listener_error(&mut self, _id: ListenerId, err: &(dyn std::error::Error + 'static)) {
-        panic!("listener error {:?}", err);
+    fn inject_listener_error(&mut self, id: ListenerId, err: &(dyn std::error::Error + 'static)) {
+        tracing::warn!(listener = ?id, %err, "Listener error.");
     }
 
-    fn inject_listener_closed(&mut self, _id: ListenerId, reason: Result<(), &std::io::Error>) {
-        panic!("listener closed {:?}", reason);
+    fn inject_listener_closed(&mut self, id: ListenerId, reason: Result<(), &std::io::Error>) {
+        tracing::debug!(listener = ?id, ?reason, "Listener closed.");
     }
 
     fn poll(
@@ -92,7 +515,7 @@ impl NetworkBehaviour for Perf {
         _cx: &mut Context,
         _params: &mut impl PollParameters,
     ) -> Poll<NetworkBehaviourAction<Self::OutEvent, Self::ConnectionHandler>> {
-        if let Some(action) = self.outbox.pop() {
+        if let Some(action) = self.outbox.pop_front() {
             return Poll::Ready(action);
         }
 
@@ -100,22 +523,97 @@ impl NetworkBehaviour for Perf {
     }
 }
 
+/// Result of a successful perf run.
 #[derive(Debug, Clone)]
+pub struct RunResult {
+    /// Set if the run was started by the local node, i.e. the local node is
+    /// the sender.
+    pub id: Option<RunId>,
+    pub peer_id: PeerId,
+    pub connection: ConnectionId,
+    pub remote_address: Multiaddr,
+    pub role: Role,
+    /// Negotiated protocol name, e.g. `/perf/0.1.0`.
+    pub protocol: String,
+    /// Parameters the run was started with. `None` if started by the remote.
+    pub params: Option<RunParams>,
+    pub sent: usize,
+    pub received: usize,
+    pub started_at: SystemTime,
+    pub finished_at: SystemTime,
+    pub stats: RunStats,
+    /// CPU time used by the whole process during the run, if available.
+    pub cpu: Option<CpuUsage>,
+    /// Memory used by the whole process during the run, if available.
+    pub memory: Option<MemoryUsage>,
+}
+
+#[derive(Debug)]
 pub enum PerfEvent {
-    PerfRunDone(Duration, usize),
+    PerfRunDone(Box<RunResult>),
+    PerfRunFailed {
+        /// Set if the run was started by the local node.
+        id: Option<RunId>,
+        peer_id: PeerId,
+        error: io::Error,
+    },
+    /// A perf run started by a remote peer was refused.
+    InboundRunRejected {
+        peer_id: PeerId,
+        connection: ConnectionId,
+        reason: RejectReason,
+    },
+    /// An inbound connection from a peer not in [`PerfConfig::allowed_peers`]
+    /// is being closed.
+    PeerNotAllowed {
+        peer_id: PeerId,
+        connection: ConnectionId,
+    },
 }
 
 impl fmt::Display for PerfEvent {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
-            PerfEvent::PerfRunDone(duration, transfered) => write!(
-                f,
-                "Interval\tTransfer\tBandwidth\n\
-                 0 s - {:.2} s\t{:?} MBytes\t{:.2} MBit/s",
-                duration.as_secs_f64(),
-                transfered / 1000 / 1000,
-                (transfered / 1000 / 1000 * 8) as f64 / duration.as_secs_f64()
-            ),
+            PerfEvent::PerfRunDone(result) => {
+                let RunResult { stats, cpu, .. } = &**result;
+                write!(
+                    f,
+                    "Interval\tTransfer\tBandwidth\n\
+                     0 s - {:.2} s\t{:?} MBytes\t{:.2} MBit/s",
+                    stats.duration.as_secs_f64(),
+                    stats.transfered / 1000 / 1000,
+                    (stats.transfered / 1000 / 1000 * 8) as f64 / stats.duration.as_secs_f64()
+                )?;
+                if let Some(overshoot) = stats.overshoot {
+                    write!(f, "\nOvershoot: {:.2} ms", overshoot.as_secs_f64() * 1000.0)?;
+                }
+                if let Some(cpu) = cpu {
+                    write!(f, "\nCPU: ")?;
+                    if let Some(percent) = cpu.percent() {
+                        write!(f, "{:.1} % ", percent)?;
+                    }
+                    write!(
+                        f,
+                        "(user {:.2} s, system {:.2} s)",
+                        cpu.user.as_secs_f64(),
+                        cpu.system.as_secs_f64(),
+                    )?;
+                    if let Some(bytes) = cpu.bytes_per_cpu_second(stats.transfered) {
+                        write!(f, ", {:.2} MBytes per CPU second", bytes / 1000.0 / 1000.0)?;
+                    }
+                }
+                if let Some(write_timing) = &stats.write_timing {
+                    write!(f, "\n{}", write_timing)?;
+                }
+                Ok(())
+            }
+            PerfEvent::PerfRunFailed { error, .. } => write!(f, "Perf run failed: {}", error),
+            PerfEvent::InboundRunRejected {
+                peer_id, reason, ..
+            } => write!(f, "Rejected perf run from {}: {}", peer_id, reason),
+            PerfEvent::PeerNotAllowed { peer_id, .. } => {
+                write!(f, "Closing connection from peer {} not allowed.", peer_id)
+            }
         }
     }
 }
@@ -1,8 +1,20 @@
 use futures::prelude::*;
-use libp2p::swarm::SwarmEvent;
-use libp2p::{identity, Multiaddr, PeerId, Swarm};
-use libp2p_perf::{build_transport, Perf, TransportSecurity};
+use libp2p::core::multiaddr::Protocol;
+use libp2p::swarm::{DialError, SwarmEvent};
+use libp2p::{Multiaddr, PeerId, Swarm};
+use libp2p_perf::{
+    build_transport, generate_keypair, read_keypair, EmulatorOpts, KeyType, Payload, Perf,
+    PerfConfig, PerfEvent, Report, RunParams, Summary, TcpOpts, TransportConfig, TransportSecurity,
+};
+use std::path::PathBuf;
+use std::time::Duration;
 use structopt::StructOpt;
+use tracing_subscriber::fmt::format::FmtSpan;
+use tracing_subscriber::EnvFilter;
+
+#[cfg(feature = "count-allocations")]
+#[global_allocator]
+static ALLOC: libp2p_perf::CountingAllocator = libp2p_perf::CountingAllocator;
 
 #[derive(Debug, StructOpt)]
 #[structopt(
@@ -15,43 +27,212 @@ struct Opt {
 
     #[structopt(long)]
     transport_security: Option<TransportSecurity>,
+
+    /// Private key file, either protobuf, PEM or PKCS#8 DER encoded, e.g. as
+    /// generated by the `keygen` binary.
+    #[structopt(long, alias = "private-key")]
+    identity: Option<PathBuf>,
+
+    /// Type of the key generated when no identity file is given. One of
+    /// 'ed25519', 'secp256k1' or 'ecdsa'.
+    #[structopt(long, default_value = "ed25519")]
+    key_type: KeyType,
+
+    #[structopt(flatten)]
+    tcp: TcpOpts,
+
+    #[structopt(flatten)]
+    emulation: EmulatorOpts,
+
+    /// Number of perf runs to execute one after another.
+    #[structopt(long, default_value = "1")]
+    repetitions: usize,
+
+    /// Pause between two runs in milliseconds.
+    #[structopt(long, default_value = "0")]
+    pause_ms: u64,
+
+    /// Run each repetition on a new connection instead of reusing the first
+    /// one.
+    #[structopt(long)]
+    fresh_connections: bool,
+
+    /// File to write the results of all runs to as JSON, e.g. to be used with
+    /// the `compare` binary.
+    #[structopt(long)]
+    json_output: Option<PathBuf>,
+
+    /// Print the peak resident set size of the process after each run, as
+    /// well as the allocations during the run when built with the
+    /// `count-allocations` feature.
+    #[structopt(long)]
+    report_memory: bool,
+
+    /// Data to send, one of 'zeros', 'random[:<seed>]', 'text:<text>' or
+    /// 'file:<path>'. The data is sent over and over again until the run
+    /// ends, random data repeating every 4 MiB.
+    #[structopt(long, default_value = "zeros")]
+    payload: Payload,
 }
 
 #[async_std::main]
 async fn main() {
-    env_logger::init();
+    // Report the time spent in each span, e.g. in each state of a perf run,
+    // via `RUST_LOG=libp2p_perf=debug`.
+    tracing_subscriber::fmt()
+        .with_env_filter(EnvFilter::from_default_env())
+        .with_span_events(FmtSpan::CLOSE)
+        .init();
     let opt = Opt::from_args();
 
-    let key = identity::Keypair::generate_ed25519();
+    let key = match &opt.identity {
+        Some(path) => read_keypair(path).unwrap(),
+        None => generate_keypair(opt.key_type),
+    };
     let local_peer_id = PeerId::from(key.public());
 
     let transport = build_transport(
         false,
         key,
         opt.transport_security.unwrap_or(TransportSecurity::Noise),
+        TransportConfig {
+            emulation: opt.emulation.config(),
+            ..opt.tcp.config()
+        },
     )
     .unwrap();
-    let perf = Perf::default();
+    // Runs are started explicitly, to be able to repeat them, thus keep the
+    // connection alive across the pause between two runs.
+    let perf = Perf::new(PerfConfig {
+        auto_start: false,
+        connection_idle_timeout: PerfConfig::default().connection_idle_timeout
+            + Duration::from_millis(opt.pause_ms),
+        ..PerfConfig::default()
+    });
     let mut client = Swarm::with_async_std_executor(transport, perf, local_peer_id);
+    let params = RunParams {
+        payload: opt.payload.clone(),
+        ..RunParams::default()
+    };
 
-    client.dial(opt.server_address).unwrap();
+    let expected_peer_id = opt.server_address.iter().find_map(|p| match p {
+        Protocol::P2p(hash) => Some(PeerId::from_multihash(hash).unwrap_or_else(|_| {
+            eprintln!("Invalid peer ID in server address {}.", opt.server_address);
+            std::process::exit(1);
+        })),
+        _ => None,
+    });
+
+    client.dial(opt.server_address.clone()).unwrap();
+
+    let mut remote_peer_id = None;
+    // Throughput of each finished run in bit/s.
+    let mut throughputs = Vec::new();
+    let mut report = Report::default();
+    // Whether the next run waits for the previous connection to be closed.
+    let mut reconnecting = false;
 
     loop {
         match client.next().await.expect("Infinite stream.") {
-            SwarmEvent::Behaviour(e) => {
+            SwarmEvent::Behaviour(PerfEvent::PerfRunFailed { error, .. }) => {
+                eprintln!("Perf run failed: {}", error);
+                std::process::exit(1);
+            }
+            SwarmEvent::Behaviour(e @ PerfEvent::PerfRunDone(_)) => {
+                let peer_id = remote_peer_id.expect("Run on established connection.");
+                if throughputs.is_empty() {
+                    println!("Remote peer ID: {}", peer_id);
+                }
+                if opt.repetitions > 1 {
+                    println!("Run {}/{}", throughputs.len() + 1, opt.repetitions);
+                }
                 println!("{}", e);
+                if let PerfEvent::PerfRunDone(result) = e {
+                    if let Some(memory) = result.memory.filter(|_| opt.report_memory) {
+                        println!("{}", memory);
+                    }
+                    throughputs.push(result.stats.throughput());
+                    report.runs.push(result.as_ref().into());
+                }
+
+                let last_run = throughputs.len() >= opt.repetitions;
+                if last_run || opt.fresh_connections {
+                    // TODO: Fix hack
+                    //
+                    // Performance run timer has already been stopped. Wait for a second
+                    // to make sure the receiving side of the substream on the server is
+                    // closed before the whole connection is dropped.
+                    async_std::task::sleep(Duration::from_secs(1)).await;
+                }
 
-                // TODO: Fix hack
-                //
-                // Performance run timer has already been stopped. Wait for a second
-                // to make sure the receiving side of the substream on the server is
-                // closed before the whole connection is dropped.
-                std::thread::sleep(std::time::Duration::from_secs(1));
+                if last_run {
+                    break;
+                }
 
-                break;
+                async_std::task::sleep(Duration::from_millis(opt.pause_ms)).await;
+
+                if opt.fresh_connections {
+                    // The run is started once the connection is closed, making
+                    // the behaviour dial a new one.
+                    let _ = client.disconnect_peer_id(peer_id);
+                    reconnecting = true;
+                } else {
+                    client.behaviour_mut().start_run(peer_id, params.clone());
+                }
+            }
+            SwarmEvent::Behaviour(e) => println!("{}", e),
+            SwarmEvent::ConnectionEstablished { peer_id, .. } => {
+                if let Some(expected) = expected_peer_id {
+                    if peer_id != expected {
+                        wrong_peer_id(expected, peer_id);
+                    }
+                }
+                if remote_peer_id.is_none() {
+                    remote_peer_id = Some(peer_id);
+                    client.behaviour_mut().start_run(peer_id, params.clone());
+                }
+            }
+            SwarmEvent::OutgoingConnectionError {
+                error: DialError::WrongPeerId { obtained, .. },
+                ..
+            } => wrong_peer_id(
+                expected_peer_id
+                    .expect("Only dialing with a peer ID can fail with a wrong peer ID."),
+                obtained,
+            ),
+            SwarmEvent::ConnectionClosed { peer_id, .. } if reconnecting => {
+                reconnecting = false;
+                client.behaviour_mut().start_run(peer_id, params.clone());
             }
-            SwarmEvent::ConnectionEstablished { .. } => {}
+            // Reported as a failed perf run if the run is still pending.
+            SwarmEvent::ConnectionClosed { .. } => {}
+            SwarmEvent::Dialing(_) => {}
             e => panic!("{:?}", e),
         }
     }
+
+    if let Some(path) = &opt.json_output {
+        report.write(path).unwrap();
+    }
+
+    if let Some(summary) = Summary::new(&throughputs).filter(|s| s.count > 1) {
+        println!(
+            "\nRuns\tMean\tStddev\tMin\tMax\t95% CI\n\
+             {}\t{:.2} MBit/s\t{:.2} MBit/s\t{:.2} MBit/s\t{:.2} MBit/s\t± {:.2} MBit/s",
+            summary.count,
+            summary.mean / 1e6,
+            summary.stddev / 1e6,
+            summary.min / 1e6,
+            summary.max / 1e6,
+            summary.ci95 / 1e6,
+        );
+    }
+}
+
+fn wrong_peer_id(expected: PeerId, obtained: PeerId) -> ! {
+    eprintln!(
+        "Expected server peer ID {} but authenticated peer ID is {}.",
+        expected, obtained
+    );
+    std::process::exit(1);
 }
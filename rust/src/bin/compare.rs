@@ -0,0 +1,95 @@
+use libp2p_perf::{Comparison, Report};
+use std::path::{Path, PathBuf};
+use structopt::StructOpt;
+
+#[derive(Debug, StructOpt)]
+#[structopt(
+    name = "libp2p-perf compare",
+    about = "Compare perf results against a baseline, exiting with 1 on a throughput regression.",
+    after_help = "Only throughput is checked against the threshold. The overshoot, i.e. the time \
+                  the sender kept sending past the run duration, is reported but never fails \
+                  the comparison. Latency is not measured, thus not compared."
+)]
+struct Opt {
+    /// JSON results of the baseline, e.g. as written by the client via
+    /// `--json-output`.
+    baseline: PathBuf,
+
+    /// JSON results to compare against the baseline.
+    current: PathBuf,
+
+    /// Maximum tolerated throughput decrease in percent.
+    #[structopt(long, default_value = "5")]
+    threshold: f64,
+}
+
+fn main() {
+    env_logger::init();
+    let opt = Opt::from_args();
+
+    let baseline = read(&opt.baseline);
+    let current = read(&opt.current);
+
+    let throughput = match Comparison::new(&baseline.throughputs(), &current.throughputs()) {
+        Some(throughput) => throughput,
+        None => {
+            eprintln!("Both result files need to contain at least one run.");
+            std::process::exit(2);
+        }
+    };
+
+    println!("Metric\tBaseline\tCurrent\tChange\tSignificant");
+    println!(
+        "Throughput\t{:.2} ± {:.2} MBit/s\t{:.2} ± {:.2} MBit/s\t{}\t{}",
+        throughput.baseline.mean / 1e6,
+        throughput.baseline.ci95 / 1e6,
+        throughput.current.mean / 1e6,
+        throughput.current.ci95 / 1e6,
+        change(&throughput),
+        significance(&throughput),
+    );
+    // Time the sender kept sending past the run duration, i.e. how late the
+    // end of the run was observed. Not a latency. Only reported, not checked.
+    if let Some(overshoot) = Comparison::new(&baseline.overshoots(), &current.overshoots()) {
+        println!(
+            "Overshoot\t{:.2} ± {:.2} ms\t{:.2} ± {:.2} ms\t{}\t{}",
+            overshoot.baseline.mean * 1000.0,
+            overshoot.baseline.ci95 * 1000.0,
+            overshoot.current.mean * 1000.0,
+            overshoot.current.ci95 * 1000.0,
+            change(&overshoot),
+            significance(&overshoot),
+        );
+    }
+
+    if throughput.regressed(opt.threshold / 100.0) {
+        eprintln!(
+            "Throughput regressed by {:.2} %, more than the threshold of {} %.",
+            -throughput.relative_change.unwrap_or_default() * 100.0,
+            opt.threshold
+        );
+        std::process::exit(1);
+    }
+}
+
+fn read(path: &Path) -> Report {
+    Report::read(path).unwrap_or_else(|e| {
+        eprintln!("Failed to read {}: {}", path.display(), e);
+        std::process::exit(2);
+    })
+}
+
+fn change(comparison: &Comparison) -> String {
+    match comparison.relative_change {
+        Some(change) => format!("{:+.2} %", change * 100.0),
+        None => "n/a".to_string(),
+    }
+}
+
+fn significance(comparison: &Comparison) -> &'static str {
+    match comparison.significant {
+        Some(true) => "yes",
+        Some(false) => "no",
+        None => "n/a",
+    }
+}
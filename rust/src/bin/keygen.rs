@@ -0,0 +1,37 @@
+use libp2p::PeerId;
+use libp2p_perf::{generate_keypair, write_keypair, KeyType};
+use std::path::PathBuf;
+use structopt::StructOpt;
+
+#[derive(Debug, StructOpt)]
+#[structopt(
+    name = "libp2p-perf keygen",
+    about = "Generate a libp2p identity and print its peer ID."
+)]
+struct Opt {
+    /// File to write the protobuf encoded private key to.
+    #[structopt(long)]
+    output: PathBuf,
+
+    /// One of 'ed25519', 'secp256k1' or 'ecdsa'.
+    #[structopt(long, default_value = "ed25519")]
+    key_type: KeyType,
+
+    /// Overwrite an existing file.
+    #[structopt(long)]
+    force: bool,
+}
+
+fn main() {
+    env_logger::init();
+    let opt = Opt::from_args();
+
+    if opt.force && opt.output.exists() {
+        std::fs::remove_file(&opt.output).unwrap();
+    }
+
+    let key = generate_keypair(opt.key_type);
+    write_keypair(&opt.output, &key).unwrap();
+
+    println!("{}", PeerId::from(key.public()));
+}
@@ -1,10 +1,26 @@
-use futures::future::poll_fn;
+use async_std::net::{SocketAddr, TcpListener, TcpStream};
+use futures::future::Either;
 use futures::prelude::*;
-use libp2p::{identity, Multiaddr, PeerId, Swarm};
-use libp2p_perf::{build_transport, Perf, TransportSecurity};
+use libp2p::core::multiaddr::Protocol;
+use libp2p::swarm::SwarmEvent;
+use libp2p::{Multiaddr, PeerId, Swarm};
+use libp2p_perf::{
+    build_transport, generate_keypair, read_keypair, KeyType, Metrics, Perf, PerfConfig, PerfEvent,
+    TcpOpts, TransportConfig, TransportSecurity,
+};
+use prometheus_client::encoding::text::encode;
+use prometheus_client::registry::Registry;
+use std::collections::HashSet;
+use std::io;
 use std::path::PathBuf;
-use std::task::Poll;
+use std::time::Duration;
 use structopt::StructOpt;
+use tracing_subscriber::fmt::format::FmtSpan;
+use tracing_subscriber::EnvFilter;
+
+#[cfg(feature = "count-allocations")]
+#[global_allocator]
+static ALLOC: libp2p_perf::CountingAllocator = libp2p_perf::CountingAllocator;
 
 #[derive(Debug, StructOpt)]
 #[structopt(
@@ -15,45 +31,283 @@ struct Opt {
     #[structopt(long)]
     listen_address: Multiaddr,
 
+    /// File to write the dialable listen addresses to, one per line. Kept up
+    /// to date as listen addresses change.
+    #[structopt(long)]
+    listen_addresses_file: Option<PathBuf>,
+
+    /// Private key file, either protobuf, PEM or PKCS#8 DER encoded, e.g. as
+    /// generated by the `keygen` binary.
+    #[structopt(long, aliases = &["private-key", "private-key-pkcs8"])]
+    identity: Option<PathBuf>,
+
+    /// Type of the key generated when no identity file is given. One of
+    /// 'ed25519', 'secp256k1' or 'ecdsa'.
+    #[structopt(long, default_value = "ed25519")]
+    key_type: KeyType,
+
+    #[structopt(flatten)]
+    tcp: TcpOpts,
+
+    /// Maximum number of concurrent perf runs. Further runs are rejected.
+    #[structopt(long)]
+    max_runs: Option<usize>,
+
+    /// Maximum number of concurrent perf runs per client peer. Further runs
+    /// are rejected.
+    #[structopt(long)]
+    max_runs_per_peer: Option<usize>,
+
+    /// Peer ID allowed to run perf tests against this server. Can be given
+    /// multiple times. Anyone is allowed if neither this nor
+    /// `--allowlist-file` is given.
+    #[structopt(long = "allow-peer")]
+    allowed_peers: Vec<PeerId>,
+
+    /// File with peer IDs allowed to run perf tests against this server, one
+    /// per line. Empty lines and lines starting with '#' are ignored.
     #[structopt(long)]
-    private_key_pkcs8: Option<PathBuf>,
+    allowlist_file: Option<PathBuf>,
+
+    /// Maximum duration of a perf run in seconds. Longer runs are aborted.
+    #[structopt(long)]
+    max_run_duration: Option<u64>,
+
+    /// Maximum number of bytes received per perf run. Runs sending more are
+    /// aborted.
+    #[structopt(long)]
+    max_run_bytes: Option<usize>,
+
+    /// Abort perf runs not sending any data for the given number of seconds.
+    #[structopt(long)]
+    idle_timeout: Option<u64>,
+
+    /// Print the peak resident set size of the process after each run, as
+    /// well as the allocations during the run when built with the
+    /// `count-allocations` feature.
+    #[structopt(long)]
+    report_memory: bool,
+
+    /// Serve Prometheus metrics via HTTP at `/metrics` on the given address,
+    /// e.g. `127.0.0.1:9993`.
+    #[structopt(long)]
+    metrics_address: Option<SocketAddr>,
+}
+
+struct MetricsServer {
+    listener: TcpListener,
+    registry: Registry,
+    metrics: Metrics,
 }
 
 #[async_std::main]
 async fn main() {
-    env_logger::init();
+    // Report the time spent in each span, e.g. in each state of a perf run,
+    // via `RUST_LOG=libp2p_perf=debug`.
+    tracing_subscriber::fmt()
+        .with_env_filter(EnvFilter::from_default_env())
+        .with_span_events(FmtSpan::CLOSE)
+        .init();
     let opt = Opt::from_args();
 
-    let key = if let Some(path) = opt.private_key_pkcs8 {
-        let mut bytes = std::fs::read(path).unwrap();
-        identity::Keypair::rsa_from_pkcs8(&mut bytes).unwrap()
-    } else {
-        identity::Keypair::generate_ed25519()
+    let key = match &opt.identity {
+        Some(path) => read_keypair(path).unwrap(),
+        None => generate_keypair(opt.key_type),
     };
     let local_peer_id = PeerId::from(key.public());
 
-    let transport = build_transport(false, key, TransportSecurity::All).unwrap();
-    let perf = Perf::default();
+    let metrics_server = match opt.metrics_address {
+        Some(address) => {
+            let mut registry = Registry::default();
+            let metrics = Metrics::new(&mut registry);
+            let listener = TcpListener::bind(address).await.unwrap();
+            println!("Serving metrics on http://{}/metrics.", address);
+            Some(MetricsServer {
+                listener,
+                registry,
+                metrics,
+            })
+        }
+        None => None,
+    };
+
+    let transport = build_transport(
+        false,
+        key,
+        TransportSecurity::All,
+        TransportConfig {
+            metrics: metrics_server.as_ref().map(|m| m.metrics.clone()),
+            ..opt.tcp.config()
+        },
+    )
+    .unwrap();
+    let allowed_peers = allowed_peers(&opt).unwrap();
+    let perf = Perf::new(PerfConfig {
+        passive: true,
+        allowed_peers,
+        max_inbound_runs: opt.max_runs,
+        max_inbound_runs_per_peer: opt.max_runs_per_peer,
+        max_inbound_run_duration: opt.max_run_duration.map(Duration::from_secs),
+        max_inbound_run_bytes: opt.max_run_bytes,
+        inbound_idle_timeout: opt.idle_timeout.map(Duration::from_secs),
+        ..PerfConfig::default()
+    });
     let mut server = Swarm::with_async_std_executor(transport, perf, local_peer_id);
 
     server.listen_on(opt.listen_address).unwrap();
-    let mut listening = false;
-
-    poll_fn(|cx| loop {
-        match server.poll_next_unpin(cx) {
-            Poll::Ready(Some(e)) => println!("{:?}", e),
-            Poll::Ready(None) => panic!("Unexpected server termination."),
-            Poll::Pending => {
-                if !listening {
-                    if let Some(a) = Swarm::listeners(&server).next() {
-                        println!("Listening on {:?}.", a);
-                        listening = true;
+
+    // When listening on a wildcard address, the TCP transport reports each
+    // interface address as a separate new listen address.
+    let mut listen_addresses = Vec::new();
+
+    loop {
+        let event = match &metrics_server {
+            Some(metrics_server) => {
+                let next = futures::select! {
+                    event = server.select_next_some() => Either::Left(event),
+                    stream = metrics_server.listener.accept().fuse() => Either::Right(stream),
+                };
+                match next {
+                    Either::Left(event) => event,
+                    Either::Right(Ok((stream, _))) => {
+                        // Runs don't emit an event when starting, thus refresh
+                        // the gauge right before encoding.
+                        metrics_server
+                            .metrics
+                            .set_active_runs(server.behaviour().inbound_runs());
+                        let mut body = Vec::new();
+                        encode(&mut body, &metrics_server.registry).unwrap();
+                        // Don't keep connections of stalled clients around.
+                        async_std::task::spawn(async move {
+                            match async_std::future::timeout(
+                                Duration::from_secs(5),
+                                serve_metrics(stream, body),
+                            )
+                            .await
+                            {
+                                Ok(Ok(())) => {}
+                                Ok(Err(error)) => {
+                                    tracing::debug!(%error, "Failed to serve metrics.")
+                                }
+                                Err(_) => tracing::debug!("Timed out serving metrics."),
+                            }
+                        });
+                        continue;
+                    }
+                    Either::Right(Err(e)) => {
+                        tracing::warn!(error = %e, "Failed to accept metrics connection.");
+                        continue;
                     }
                 }
+            }
+            None => server.next().await.expect("Infinite stream."),
+        };
 
-                return Poll::Pending;
+        match event {
+            SwarmEvent::NewListenAddr { address, .. } => {
+                let address = address.with(Protocol::P2p(local_peer_id.into()));
+                println!("Listening on {}.", address);
+                listen_addresses.push(address);
+                write_listen_addresses(&opt.listen_addresses_file, &listen_addresses);
+            }
+            SwarmEvent::ExpiredListenAddr { address, .. } => {
+                let address = address.with(Protocol::P2p(local_peer_id.into()));
+                println!("No longer listening on {}.", address);
+                listen_addresses.retain(|a| a != &address);
+                write_listen_addresses(&opt.listen_addresses_file, &listen_addresses);
+            }
+            SwarmEvent::Behaviour(e) => {
+                match &e {
+                    PerfEvent::InboundRunRejected { .. } | PerfEvent::PeerNotAllowed { .. } => {
+                        println!("{}", e)
+                    }
+                    // Same output as for any other swarm event.
+                    e => println!("Behaviour({:?})", e),
+                }
+                if let Some(metrics_server) = &metrics_server {
+                    metrics_server.metrics.record(&e);
+                }
+                if let PerfEvent::PerfRunDone(result) = e {
+                    if let Some(memory) = result.memory {
+                        if opt.report_memory {
+                            println!("{}", memory);
+                        }
+                    }
+                }
             }
+            e => println!("{:?}", e),
         }
-    })
-    .await
+    }
+}
+
+fn allowed_peers(opt: &Opt) -> io::Result<Option<HashSet<PeerId>>> {
+    if opt.allowed_peers.is_empty() && opt.allowlist_file.is_none() {
+        return Ok(None);
+    }
+
+    let mut allowed_peers: HashSet<PeerId> = opt.allowed_peers.iter().copied().collect();
+
+    if let Some(path) = &opt.allowlist_file {
+        for line in std::fs::read_to_string(path)?.lines() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let peer_id = line.parse().map_err(|e| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("Invalid peer ID {:?} in {}: {}", line, path.display(), e),
+                )
+            })?;
+            allowed_peers.insert(peer_id);
+        }
+    }
+
+    Ok(Some(allowed_peers))
+}
+
+/// Answers a single HTTP request with the given metrics if it is for
+/// `/metrics`.
+async fn serve_metrics(stream: TcpStream, body: Vec<u8>) -> io::Result<()> {
+    let mut reader = futures::io::BufReader::new(&stream);
+
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line).await?;
+    // Skip the headers.
+    loop {
+        let mut header = String::new();
+        if reader.read_line(&mut header).await? == 0 || header.trim().is_empty() {
+            break;
+        }
+    }
+
+    let mut stream = &stream;
+    if request_line.split_whitespace().nth(1) == Some("/metrics") {
+        stream
+            .write_all(
+                format!(
+                    "HTTP/1.1 200 OK\r\n\
+                     Content-Type: application/openmetrics-text; version=1.0.0; charset=utf-8\r\n\
+                     Content-Length: {}\r\n\
+                     Connection: close\r\n\r\n",
+                    body.len()
+                )
+                .as_bytes(),
+            )
+            .await?;
+        stream.write_all(&body).await?;
+    } else {
+        stream
+            .write_all(b"HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n")
+            .await?;
+    }
+    stream.flush().await
+}
+
+fn write_listen_addresses(path: &Option<PathBuf>, addresses: &[Multiaddr]) {
+    if let Some(path) = path {
+        let content: String = addresses.iter().map(|a| format!("{}\n", a)).collect();
+        std::fs::write(path, content).unwrap();
+    }
 }
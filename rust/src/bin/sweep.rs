@@ -0,0 +1,219 @@
+use futures::prelude::*;
+use libp2p::swarm::SwarmEvent;
+use libp2p::{identity, Multiaddr, PeerId, Swarm};
+use libp2p_perf::{
+    build_transport, EmulatorOpts, Perf, PerfConfig, PerfEvent, TransportConfig, TransportSecurity,
+};
+use std::time::Duration;
+use structopt::StructOpt;
+use tracing_subscriber::fmt::format::FmtSpan;
+use tracing_subscriber::EnvFilter;
+
+#[derive(Debug, StructOpt)]
+#[structopt(
+    name = "libp2p-perf sweep",
+    about = "Run libp2p-perf over the cartesian product of the given parameters."
+)]
+struct Opt {
+    /// Run against an external server instead of an in-process one. Only the
+    /// client side of each parameter is applied in that case.
+    #[structopt(long)]
+    server_address: Option<Multiaddr>,
+
+    /// Connect to the in-process server via loopback TCP instead of the
+    /// memory transport.
+    #[structopt(long)]
+    tcp: bool,
+
+    /// Yamux receive windows in bytes.
+    #[structopt(long, use_delimiter = true, default_value = "262144,16777216")]
+    yamux_receive_window: Vec<u32>,
+
+    /// Sender and receiver buffer sizes in bytes.
+    #[structopt(long, use_delimiter = true, default_value = "8000,128000")]
+    buffer_size: Vec<usize>,
+
+    #[structopt(long, use_delimiter = true, default_value = "noise,plaintext")]
+    transport_security: Vec<TransportSecurity>,
+
+    /// Duration of each run in seconds.
+    #[structopt(long, default_value = "10")]
+    duration: u64,
+
+    #[structopt(flatten)]
+    emulation: EmulatorOpts,
+}
+
+struct Params {
+    transport_security: TransportSecurity,
+    yamux_receive_window: u32,
+    buffer_size: usize,
+}
+
+#[async_std::main]
+async fn main() {
+    // Report the time spent in each span, e.g. in each state of a perf run,
+    // via `RUST_LOG=libp2p_perf=debug`.
+    tracing_subscriber::fmt()
+        .with_env_filter(EnvFilter::from_default_env())
+        .with_span_events(FmtSpan::CLOSE)
+        .init();
+    let opt = Opt::from_args();
+
+    let mut params = Vec::new();
+    for transport_security in &opt.transport_security {
+        for yamux_receive_window in &opt.yamux_receive_window {
+            for buffer_size in &opt.buffer_size {
+                params.push(Params {
+                    transport_security: *transport_security,
+                    yamux_receive_window: *yamux_receive_window,
+                    buffer_size: *buffer_size,
+                });
+            }
+        }
+    }
+
+    println!("Security\tYamux window\tBuffer size\tInterval\tTransfer\tBandwidth");
+
+    for p in params {
+        let transport_config = TransportConfig {
+            yamux_receive_window_size: p.yamux_receive_window,
+            yamux_max_buffer_size: std::cmp::max(
+                p.yamux_receive_window as usize,
+                TransportConfig::default().yamux_max_buffer_size,
+            ),
+            emulation: opt.emulation.config(),
+            ..TransportConfig::default()
+        };
+        let perf_config = PerfConfig {
+            buffer_size: p.buffer_size,
+            run_duration: Duration::from_secs(opt.duration),
+            ..PerfConfig::default()
+        };
+
+        let result = match &opt.server_address {
+            Some(address) => {
+                run(
+                    false,
+                    address.clone(),
+                    p.transport_security,
+                    transport_config,
+                    perf_config,
+                )
+                .await
+            }
+            None => {
+                let (address, server) =
+                    spawn_server(!opt.tcp, transport_config.clone(), perf_config.clone()).await;
+                let result = run(
+                    !opt.tcp,
+                    address,
+                    p.transport_security,
+                    transport_config,
+                    perf_config,
+                )
+                .await;
+                server.cancel().await;
+                result
+            }
+        };
+
+        match result {
+            Ok((duration, transfered)) => println!(
+                "{}\t{}\t{}\t0 s - {:.2} s\t{} MBytes\t{:.2} MBit/s",
+                p.transport_security,
+                p.yamux_receive_window,
+                p.buffer_size,
+                duration.as_secs_f64(),
+                transfered / 1000 / 1000,
+                (transfered * 8) as f64 / 1000.0 / 1000.0 / duration.as_secs_f64(),
+            ),
+            Err(e) => println!(
+                "{}\t{}\t{}\tfailed: {}",
+                p.transport_security, p.yamux_receive_window, p.buffer_size, e,
+            ),
+        }
+    }
+}
+
+async fn spawn_server(
+    in_memory: bool,
+    transport_config: TransportConfig,
+    perf_config: PerfConfig,
+) -> (Multiaddr, async_std::task::JoinHandle<()>) {
+    let key = identity::Keypair::generate_ed25519();
+    let local_peer_id = PeerId::from(key.public());
+
+    let transport =
+        build_transport(in_memory, key, TransportSecurity::All, transport_config).unwrap();
+    let perf = Perf::new(PerfConfig {
+        passive: true,
+        ..perf_config
+    });
+    let mut server = Swarm::with_async_std_executor(transport, perf, local_peer_id);
+
+    let listen_address: Multiaddr = if in_memory {
+        "/memory/0".parse().unwrap()
+    } else {
+        "/ip4/127.0.0.1/tcp/0".parse().unwrap()
+    };
+    server.listen_on(listen_address).unwrap();
+
+    let address = loop {
+        if let SwarmEvent::NewListenAddr { address, .. } =
+            server.next().await.expect("Infinite stream.")
+        {
+            break address;
+        }
+    };
+
+    let handle = async_std::task::spawn(async move {
+        loop {
+            server.next().await;
+        }
+    });
+
+    (address, handle)
+}
+
+async fn run(
+    in_memory: bool,
+    address: Multiaddr,
+    transport_security: TransportSecurity,
+    transport_config: TransportConfig,
+    perf_config: PerfConfig,
+) -> Result<(Duration, usize), String> {
+    let key = identity::Keypair::generate_ed25519();
+    let local_peer_id = PeerId::from(key.public());
+
+    let transport = build_transport(in_memory, key, transport_security, transport_config).unwrap();
+    let perf = Perf::new(perf_config);
+    let mut client = Swarm::with_async_std_executor(transport, perf, local_peer_id);
+
+    client.dial(address).unwrap();
+
+    loop {
+        match client.next().await.expect("Infinite stream.") {
+            SwarmEvent::Behaviour(PerfEvent::PerfRunDone(result)) => {
+                // See the equivalent hack in the client binary. Keep driving
+                // the connection for a second to make sure the server observes
+                // the closed substream before the connection is dropped.
+                let _ = async_std::future::timeout(Duration::from_secs(1), async {
+                    loop {
+                        client.next().await;
+                    }
+                })
+                .await;
+
+                return Ok((result.stats.duration, result.stats.transfered));
+            }
+            SwarmEvent::Behaviour(PerfEvent::PerfRunFailed { error, .. }) => {
+                return Err(error.to_string())
+            }
+            SwarmEvent::ConnectionEstablished { .. } => {}
+            // Reported as a failed perf run if the run is still pending.
+            SwarmEvent::ConnectionClosed { .. } => {}
+            e => panic!("{:?}", e),
+        }
+    }
+}
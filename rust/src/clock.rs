@@ -0,0 +1,210 @@
+use futures::future::BoxFuture;
+use futures::prelude::*;
+use futures_timer::Delay;
+use std::fmt;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, Waker};
+use std::time::{Duration, Instant};
+
+/// Source of time for measuring perf runs.
+pub trait Clock: fmt::Debug + Send + Sync + 'static {
+    fn now(&self) -> Instant;
+
+    /// Returns a future resolving once the clock passed `deadline`.
+    fn sleep_until(&self, deadline: Instant) -> BoxFuture<'static, ()>;
+}
+
+/// [`Clock`] backed by [`Instant::now`].
+#[derive(Debug, Default, Clone, Copy)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn sleep_until(&self, deadline: Instant) -> BoxFuture<'static, ()> {
+        Delay::new(deadline.saturating_duration_since(Instant::now())).boxed()
+    }
+}
+
+/// [`Clock`] only advancing when told to, allowing tests to run perf runs
+/// instantly and deterministically.
+#[derive(Debug)]
+pub struct VirtualClock {
+    origin: Instant,
+    state: Arc<Mutex<VirtualClockState>>,
+}
+
+#[derive(Debug, Default)]
+struct VirtualClockState {
+    elapsed: Duration,
+    /// Wakers of pending [`VirtualClock::sleep_until`] futures, keyed by the
+    /// ID of the sleep, along with their deadline, relative to the origin.
+    timers: Vec<(u64, Duration, Waker)>,
+    next_sleep_id: u64,
+}
+
+impl VirtualClockState {
+    fn advance(&mut self, duration: Duration) {
+        self.elapsed += duration;
+
+        let elapsed = self.elapsed;
+        self.timers.retain(|(_, deadline, waker)| {
+            if *deadline <= elapsed {
+                waker.wake_by_ref();
+                false
+            } else {
+                true
+            }
+        });
+    }
+}
+
+impl VirtualClock {
+    pub fn new() -> Self {
+        VirtualClock {
+            origin: Instant::now(),
+            state: Default::default(),
+        }
+    }
+
+    /// Moves the clock forward, waking all sleeps whose deadline passed.
+    pub fn advance(&self, duration: Duration) {
+        self.state.lock().unwrap().advance(duration);
+    }
+
+    /// Moves the clock forward to the earliest deadline of all pending
+    /// sleeps, waking the sleeps due by then. Returns `false` if no sleep is
+    /// pending.
+    pub fn advance_to_next_deadline(&self) -> bool {
+        let mut state = self.state.lock().unwrap();
+        match state.timers.iter().map(|(_, deadline, _)| *deadline).min() {
+            Some(deadline) => {
+                let duration = deadline.saturating_sub(state.elapsed);
+                state.advance(duration);
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+impl Default for VirtualClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Clock for VirtualClock {
+    fn now(&self) -> Instant {
+        self.origin + self.state.lock().unwrap().elapsed
+    }
+
+    fn sleep_until(&self, deadline: Instant) -> BoxFuture<'static, ()> {
+        let id = {
+            let mut state = self.state.lock().unwrap();
+            state.next_sleep_id += 1;
+            state.next_sleep_id
+        };
+        VirtualSleep {
+            id,
+            deadline: deadline.saturating_duration_since(self.origin),
+            state: self.state.clone(),
+        }
+        .boxed()
+    }
+}
+
+struct VirtualSleep {
+    id: u64,
+    deadline: Duration,
+    state: Arc<Mutex<VirtualClockState>>,
+}
+
+impl Future for VirtualSleep {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        let mut state = self.state.lock().unwrap();
+
+        if state.elapsed >= self.deadline {
+            return Poll::Ready(());
+        }
+
+        match state.timers.iter_mut().find(|(id, _, _)| *id == self.id) {
+            Some((_, _, waker)) => {
+                if !waker.will_wake(cx.waker()) {
+                    *waker = cx.waker().clone();
+                }
+            }
+            None => state
+                .timers
+                .push((self.id, self.deadline, cx.waker().clone())),
+        }
+
+        Poll::Pending
+    }
+}
+
+impl Drop for VirtualSleep {
+    fn drop(&mut self) {
+        // Don't let sleeps no longer awaited hold back or advance the clock.
+        if let Ok(mut state) = self.state.lock() {
+            state.timers.retain(|(id, _, _)| *id != self.id);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::task::noop_waker;
+
+    #[test]
+    fn virtual_sleep_fires_on_advance() {
+        let clock = VirtualClock::new();
+        let start = clock.now();
+        assert_eq!(clock.now(), start);
+
+        let waker = noop_waker();
+        let mut cx = Context::from_waker(&waker);
+        let mut sleep = clock.sleep_until(start + Duration::from_secs(1));
+        assert!(sleep.poll_unpin(&mut cx).is_pending());
+
+        clock.advance(Duration::from_millis(999));
+        assert!(sleep.poll_unpin(&mut cx).is_pending());
+
+        clock.advance(Duration::from_millis(1));
+        assert!(sleep.poll_unpin(&mut cx).is_ready());
+        assert_eq!(clock.now(), start + Duration::from_secs(1));
+    }
+
+    #[test]
+    fn advance_to_next_deadline_skips_dropped_sleeps() {
+        let clock = VirtualClock::new();
+        let start = clock.now();
+        assert!(!clock.advance_to_next_deadline());
+
+        let waker = noop_waker();
+        let mut cx = Context::from_waker(&waker);
+        let mut dropped = clock.sleep_until(start + Duration::from_secs(1));
+        let mut first = clock.sleep_until(start + Duration::from_secs(2));
+        let mut second = clock.sleep_until(start + Duration::from_secs(3));
+        assert!(dropped.poll_unpin(&mut cx).is_pending());
+        assert!(second.poll_unpin(&mut cx).is_pending());
+        assert!(first.poll_unpin(&mut cx).is_pending());
+        drop(dropped);
+
+        assert!(clock.advance_to_next_deadline());
+        assert_eq!(clock.now(), start + Duration::from_secs(2));
+        assert!(first.poll_unpin(&mut cx).is_ready());
+        assert!(second.poll_unpin(&mut cx).is_pending());
+
+        assert!(clock.advance_to_next_deadline());
+        assert_eq!(clock.now(), start + Duration::from_secs(3));
+        assert!(second.poll_unpin(&mut cx).is_ready());
+        assert!(!clock.advance_to_next_deadline());
+    }
+}
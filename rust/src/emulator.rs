@@ -0,0 +1,359 @@
+//! Connection wrapper emulating a network link with limited bandwidth, latency,
+//! jitter and loss on top of an arbitrary (e.g. in-memory) connection.
+//!
+//! Emulation is applied to the data written on a connection, i.e. in-process
+//! with both endpoints emulated each direction is affected once. When only the
+//! local endpoint is emulated, only the outgoing direction is affected.
+
+use crate::clock::Clock;
+use futures::future::BoxFuture;
+use futures::prelude::*;
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use std::collections::VecDeque;
+use std::io;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+use structopt::StructOpt;
+
+#[derive(Debug, Clone)]
+pub struct EmulatorConfig {
+    /// One-way delay added to each chunk of data written.
+    pub delay: Duration,
+    /// Maximum random delay added on top of [`EmulatorConfig::delay`]. Data is
+    /// never reordered.
+    pub jitter: Duration,
+    /// Link bandwidth in bit/s. Unlimited if `None`.
+    pub bandwidth: Option<u64>,
+    /// Probability of a chunk of data being lost.
+    ///
+    /// The emulated connection is a reliable byte stream, thus dropping data
+    /// would break the protocols on top. Instead a lost chunk is delivered
+    /// after an additional [`EmulatorConfig::retransmission_timeout`], like
+    /// TCP would do.
+    pub loss: f64,
+    pub retransmission_timeout: Duration,
+    /// Maximum number of bytes in flight on the emulated link before writes
+    /// are back-pressured.
+    pub queue_size: usize,
+    /// Seed for jitter and loss.
+    pub seed: u64,
+}
+
+impl Default for EmulatorConfig {
+    fn default() -> Self {
+        EmulatorConfig {
+            delay: Duration::ZERO,
+            jitter: Duration::ZERO,
+            bandwidth: None,
+            loss: 0.0,
+            // Minimum TCP retransmission timeout on Linux.
+            retransmission_timeout: Duration::from_millis(200),
+            // Same as the yamux receive window by default, to not be the
+            // bottleneck.
+            queue_size: 16 * 1024 * 1024,
+            seed: 0,
+        }
+    }
+}
+
+/// Command line options of the binaries enabling emulation, see
+/// [`EmulatorOpts::config`].
+#[derive(Debug, Clone, StructOpt)]
+pub struct EmulatorOpts {
+    /// Emulated one-way delay in milliseconds.
+    #[structopt(long)]
+    pub emulate_delay_ms: Option<u64>,
+
+    /// Emulated maximum jitter in milliseconds.
+    #[structopt(long)]
+    pub emulate_jitter_ms: Option<u64>,
+
+    /// Emulated bandwidth in bit/s.
+    #[structopt(long)]
+    pub emulate_bandwidth: Option<u64>,
+
+    /// Emulated probability of a chunk of data being lost and retransmitted.
+    #[structopt(long)]
+    pub emulate_loss: Option<f64>,
+}
+
+impl EmulatorOpts {
+    /// Returns the emulation to apply, `None` if no option is set.
+    pub fn config(&self) -> Option<EmulatorConfig> {
+        if self.emulate_delay_ms.is_none()
+            && self.emulate_jitter_ms.is_none()
+            && self.emulate_bandwidth.is_none()
+            && self.emulate_loss.is_none()
+        {
+            return None;
+        }
+
+        Some(EmulatorConfig {
+            delay: Duration::from_millis(self.emulate_delay_ms.unwrap_or(0)),
+            jitter: Duration::from_millis(self.emulate_jitter_ms.unwrap_or(0)),
+            bandwidth: self.emulate_bandwidth,
+            loss: self.emulate_loss.unwrap_or(0.0),
+            ..EmulatorConfig::default()
+        })
+    }
+}
+
+struct Chunk {
+    data: Vec<u8>,
+    written: usize,
+    deliver_at: Instant,
+}
+
+pub struct Emulated<C> {
+    inner: C,
+    config: EmulatorConfig,
+    rng: StdRng,
+    queue: VecDeque<Chunk>,
+    queued_bytes: usize,
+    /// Point in time at which the emulated link finished transmitting the last
+    /// queued chunk.
+    link_free_at: Instant,
+    /// Delivery time of the last queued chunk.
+    last_deliver_at: Instant,
+    clock: Arc<dyn Clock>,
+    /// Sleep until the delivery time of the first queued chunk, only
+    /// re-created once that chunk is delivered.
+    timer: Option<BoxFuture<'static, ()>>,
+    /// Delivery time `timer` is armed for.
+    timer_deadline: Option<Instant>,
+}
+
+impl<C> Emulated<C> {
+    /// Emulates the link on `inner`, timed by `clock`.
+    pub fn new(inner: C, config: EmulatorConfig, clock: Arc<dyn Clock>) -> Self {
+        let now = clock.now();
+        Emulated {
+            inner,
+            rng: StdRng::seed_from_u64(config.seed),
+            config,
+            queue: VecDeque::new(),
+            queued_bytes: 0,
+            link_free_at: now,
+            last_deliver_at: now,
+            clock,
+            timer: None,
+            timer_deadline: None,
+        }
+    }
+
+    fn enqueue(&mut self, data: &[u8]) {
+        let now = self.clock.now();
+
+        let transmission = match self.config.bandwidth {
+            Some(bandwidth) => Duration::from_secs_f64(data.len() as f64 * 8.0 / bandwidth as f64),
+            None => Duration::ZERO,
+        };
+        self.link_free_at = std::cmp::max(now, self.link_free_at) + transmission;
+
+        let mut deliver_at = self.link_free_at + self.config.delay;
+        if self.config.jitter > Duration::ZERO {
+            deliver_at += self.config.jitter.mul_f64(self.rng.gen::<f64>());
+        }
+        if self.config.loss > 0.0 && self.rng.gen_bool(self.config.loss) {
+            deliver_at += self.config.retransmission_timeout;
+        }
+        // Never reorder data.
+        deliver_at = std::cmp::max(deliver_at, self.last_deliver_at);
+        self.last_deliver_at = deliver_at;
+
+        self.queued_bytes += data.len();
+        self.queue.push_back(Chunk {
+            data: data.to_vec(),
+            written: 0,
+            deliver_at,
+        });
+    }
+}
+
+impl<C: AsyncWrite + Unpin> Emulated<C> {
+    /// Writes all due chunks to the inner connection. Returns `Ready` once the
+    /// queue is empty.
+    fn poll_deliver(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        loop {
+            let chunk = match self.queue.front_mut() {
+                Some(chunk) => chunk,
+                None => return Poll::Ready(Ok(())),
+            };
+
+            if chunk.deliver_at > self.clock.now() {
+                if self.timer_deadline != Some(chunk.deliver_at) {
+                    self.timer = Some(self.clock.sleep_until(chunk.deliver_at));
+                    self.timer_deadline = Some(chunk.deliver_at);
+                }
+                // The chunk is due once the timer fired, even if slightly
+                // ahead of `deliver_at`.
+                let timer = self.timer.as_mut().expect("Armed for the first chunk.");
+                if timer.poll_unpin(cx).is_pending() {
+                    return Poll::Pending;
+                }
+            }
+
+            match Pin::new(&mut self.inner).poll_write(cx, &chunk.data[chunk.written..]) {
+                Poll::Ready(Ok(0)) => return Poll::Ready(Err(io::ErrorKind::WriteZero.into())),
+                Poll::Ready(Ok(n)) => {
+                    chunk.written += n;
+                    self.queued_bytes -= n;
+                    if chunk.written == chunk.data.len() {
+                        self.queue.pop_front();
+                    }
+                }
+                Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+    }
+}
+
+impl<C: AsyncRead + Unpin> AsyncRead for Emulated<C> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_read(cx, buf)
+    }
+}
+
+impl<C: AsyncWrite + Unpin> AsyncWrite for Emulated<C> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+
+        if buf.is_empty() {
+            return Poll::Ready(Ok(0));
+        }
+
+        if let Poll::Ready(Err(e)) = this.poll_deliver(cx) {
+            return Poll::Ready(Err(e));
+        }
+
+        if this.queued_bytes >= this.config.queue_size {
+            // Woken up by either the timer or the inner connection.
+            return Poll::Pending;
+        }
+
+        this.enqueue(buf);
+
+        // Make sure the new chunk is delivered even if no further writes
+        // happen.
+        if let Poll::Ready(Err(e)) = this.poll_deliver(cx) {
+            return Poll::Ready(Err(e));
+        }
+
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        futures::ready!(this.poll_deliver(cx))?;
+        Pin::new(&mut this.inner).poll_flush(cx)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        futures::ready!(this.poll_deliver(cx))?;
+        Pin::new(&mut this.inner).poll_close(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::SystemClock;
+    use futures::executor::block_on;
+    use libp2p::core::multiaddr::{Multiaddr, Protocol};
+    use libp2p::core::transport::{memory::Channel, MemoryTransport, Transport, TransportEvent};
+
+    /// Returns both ends of an in-memory connection.
+    async fn connect() -> (Channel<Vec<u8>>, Channel<Vec<u8>>) {
+        let mut transport = MemoryTransport::default();
+        let address: Multiaddr = Protocol::Memory(rand::random::<u64>()).into();
+        transport.listen_on(address.clone()).unwrap();
+        let dialer = transport.dial(address).unwrap();
+
+        let listener = future::poll_fn(|cx| loop {
+            match Pin::new(&mut transport).poll(cx) {
+                Poll::Ready(TransportEvent::Incoming { upgrade, .. }) => {
+                    return Poll::Ready(upgrade)
+                }
+                Poll::Ready(_) => continue,
+                Poll::Pending => return Poll::Pending,
+            }
+        });
+        let (dialer, listener) = future::join(dialer, listener).await;
+        (dialer.unwrap(), listener.await.unwrap())
+    }
+
+    /// Writes `len` bytes through an emulated connection, returning the time
+    /// until all of them were read on the other end.
+    fn transfer(config: EmulatorConfig, len: usize) -> Duration {
+        block_on(async {
+            let (dialer, mut listener) = connect().await;
+            let mut dialer = Emulated::new(dialer, config, Arc::new(SystemClock));
+
+            let start = Instant::now();
+            let write = async {
+                for chunk in vec![0; len].chunks(1024) {
+                    dialer.write_all(chunk).await.unwrap();
+                }
+                dialer.flush().await.unwrap();
+            };
+            let read = async {
+                let mut buf = vec![0; len];
+                listener.read_exact(&mut buf).await.unwrap();
+            };
+            future::join(write, read).await;
+            start.elapsed()
+        })
+    }
+
+    #[test]
+    fn delay() {
+        let config = EmulatorConfig {
+            delay: Duration::from_millis(100),
+            ..EmulatorConfig::default()
+        };
+        assert!(transfer(config, 1) >= Duration::from_millis(100));
+    }
+
+    #[test]
+    fn bandwidth() {
+        // 100 KB at 8 Mbit/s, i.e. 1 MB/s.
+        let config = EmulatorConfig {
+            bandwidth: Some(8_000_000),
+            ..EmulatorConfig::default()
+        };
+        assert!(transfer(config, 100_000) >= Duration::from_millis(100));
+    }
+
+    #[test]
+    fn jitter() {
+        let config = EmulatorConfig {
+            jitter: Duration::from_millis(100),
+            ..EmulatorConfig::default()
+        };
+        // Without reordering, the last of 100 chunks is delivered after the
+        // largest random delay, which is next to certain to be above 50 ms.
+        let elapsed = transfer(config, 100 * 1024);
+        assert!(elapsed >= Duration::from_millis(50), "{:?}", elapsed);
+    }
+
+    #[test]
+    fn loss() {
+        let config = EmulatorConfig {
+            loss: 1.0,
+            ..EmulatorConfig::default()
+        };
+        assert!(transfer(config, 1) >= Duration::from_millis(200));
+    }
+}
@@ -0,0 +1,186 @@
+//! Connection wrapper injecting faults, used to test how perf runs behave on
+//! failures in the middle of a run.
+
+use crate::clock::Clock;
+use futures::future::BoxFuture;
+use futures::prelude::*;
+use std::io;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+#[derive(Debug, Clone)]
+pub struct FaultConfig {
+    pub fault: Fault,
+    pub trigger: FaultTrigger,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Fault {
+    /// Fail all reads and writes with [`io::ErrorKind::ConnectionReset`].
+    Reset,
+    /// Never return from reads, while writes continue to succeed.
+    StallReads,
+    /// Report writes as successful while discarding the data.
+    TruncateWrites,
+    /// Fail all reads and writes with the given error.
+    Error(io::ErrorKind),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FaultTrigger {
+    /// Inject the fault once the given number of bytes has been read and
+    /// written on the connection in total.
+    AfterBytes(usize),
+    /// Inject the fault once the given time passed since the connection was
+    /// established.
+    AfterDuration(Duration),
+}
+
+pub struct Faulty<C> {
+    inner: C,
+    fault: Fault,
+    /// Bytes left until the fault is triggered, if triggered by bytes.
+    remaining_bytes: Option<usize>,
+    /// Timer triggering the fault, if triggered by time.
+    timer: Option<BoxFuture<'static, ()>>,
+    triggered: bool,
+}
+
+impl<C> Faulty<C> {
+    /// Injects the fault on `inner`, a time-based trigger measured with
+    /// `clock`.
+    pub fn new(inner: C, config: FaultConfig, clock: Arc<dyn Clock>) -> Self {
+        let (remaining_bytes, timer, triggered) = match config.trigger {
+            FaultTrigger::AfterBytes(0) => (None, None, true),
+            FaultTrigger::AfterBytes(n) => (Some(n), None, false),
+            FaultTrigger::AfterDuration(d) => {
+                (None, Some(clock.sleep_until(clock.now() + d)), false)
+            }
+        };
+
+        Faulty {
+            inner,
+            fault: config.fault,
+            remaining_bytes,
+            timer,
+            triggered,
+        }
+    }
+
+    /// Returns whether the fault is triggered, registering the waker for the
+    /// trigger timer otherwise.
+    fn poll_triggered(&mut self, cx: &mut Context<'_>) -> bool {
+        if !self.triggered {
+            if let Some(timer) = self.timer.as_mut() {
+                if timer.poll_unpin(cx).is_ready() {
+                    self.timer = None;
+                    self.triggered = true;
+                }
+            }
+        }
+
+        self.triggered
+    }
+
+    /// Limits `len` to the bytes left until the fault is triggered.
+    fn limit(&self, len: usize) -> usize {
+        match self.remaining_bytes {
+            Some(remaining) => std::cmp::min(len, remaining),
+            None => len,
+        }
+    }
+
+    fn record(&mut self, n: usize) {
+        if let Some(remaining) = self.remaining_bytes.as_mut() {
+            *remaining -= n;
+            if *remaining == 0 {
+                self.remaining_bytes = None;
+                self.triggered = true;
+            }
+        }
+    }
+
+    fn error(&self) -> Option<io::Error> {
+        match self.fault {
+            Fault::Reset => Some(io::ErrorKind::ConnectionReset.into()),
+            Fault::Error(kind) => Some(kind.into()),
+            Fault::StallReads | Fault::TruncateWrites => None,
+        }
+    }
+}
+
+impl<C: AsyncRead + Unpin> AsyncRead for Faulty<C> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+
+        if this.poll_triggered(cx) {
+            match this.fault {
+                Fault::StallReads => return Poll::Pending,
+                Fault::TruncateWrites => {}
+                Fault::Reset | Fault::Error(_) => {
+                    return Poll::Ready(Err(this.error().expect("Fault with error.")))
+                }
+            }
+        }
+
+        let len = this.limit(buf.len());
+        let n = futures::ready!(Pin::new(&mut this.inner).poll_read(cx, &mut buf[..len]))?;
+        this.record(n);
+        Poll::Ready(Ok(n))
+    }
+}
+
+impl<C: AsyncWrite + Unpin> AsyncWrite for Faulty<C> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+
+        if this.poll_triggered(cx) {
+            match this.fault {
+                Fault::StallReads => {}
+                Fault::TruncateWrites => return Poll::Ready(Ok(buf.len())),
+                Fault::Reset | Fault::Error(_) => {
+                    return Poll::Ready(Err(this.error().expect("Fault with error.")))
+                }
+            }
+        }
+
+        let len = this.limit(buf.len());
+        let n = futures::ready!(Pin::new(&mut this.inner).poll_write(cx, &buf[..len]))?;
+        this.record(n);
+        Poll::Ready(Ok(n))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+
+        if this.poll_triggered(cx) {
+            if let Some(e) = this.error() {
+                return Poll::Ready(Err(e));
+            }
+        }
+
+        Pin::new(&mut this.inner).poll_flush(cx)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+
+        if this.poll_triggered(cx) {
+            if let Some(e) = this.error() {
+                return Poll::Ready(Err(e));
+            }
+        }
+
+        Pin::new(&mut this.inner).poll_close(cx)
+    }
+}
@@ -1,29 +1,64 @@
+use futures::future::BoxFuture;
 use futures::prelude::*;
 use futures::stream::FuturesUnordered;
 use libp2p::{
-    core::upgrade::{InboundUpgrade, OutboundUpgrade},
+    core::{
+        upgrade::{InboundUpgrade, OutboundUpgrade},
+        ConnectedPoint,
+    },
     swarm::{
-        ConnectionHandler, ConnectionHandlerEvent, ConnectionHandlerUpgrErr, KeepAlive,
-        NegotiatedSubstream, SubstreamProtocol,
+        ConnectionHandler, ConnectionHandlerEvent, ConnectionHandlerUpgrErr, IntoConnectionHandler,
+        KeepAlive, NegotiatedSubstream, SubstreamProtocol,
     },
+    PeerId,
 };
 use std::io;
 use std::pin::Pin;
+use std::sync::Arc;
 use std::task::{Context, Poll};
-use std::time::{Duration, Instant};
+use std::time::{Duration, Instant, SystemTime};
 
-use crate::protocol::PerfProtocolConfig;
+use crate::behaviour::{PerfConfig, RunId, RunParams};
+use crate::clock::Clock;
+use crate::limit::{RejectReason, RunLimiter, RunPermit};
+use crate::payload::PayloadBuffer;
+use crate::protocol::{AbortReason, PerfProtocolConfig};
+use crate::resource::{CpuUsage, MemoryUsage, ResourceUsage};
+use crate::timing::WriteTiming;
 
-// iPerf works by writing an array of len bytes a number of times. Default is
-// 128 KB for TCP, 8 KB for UDP.
-//
-// https://iperf.fr/iperf-doc.php
-const BUFFER_SIZE: usize = 128_000;
+/// Creates a [`PerfHandler`] once the remote peer of a connection is known.
+pub struct PerfHandlerProto {
+    config: PerfConfig,
+    limiter: Arc<RunLimiter>,
+}
 
-const MSG: &[u8] = &[0u8; BUFFER_SIZE];
+impl PerfHandlerProto {
+    pub fn new(config: PerfConfig, limiter: Arc<RunLimiter>) -> Self {
+        PerfHandlerProto { config, limiter }
+    }
+}
+
+impl IntoConnectionHandler for PerfHandlerProto {
+    type Handler = PerfHandler;
+
+    fn into_handler(self, remote_peer_id: &PeerId, _endpoint: &ConnectedPoint) -> PerfHandler {
+        PerfHandler::new(self.config, *remote_peer_id, self.limiter)
+    }
+
+    fn inbound_protocol(&self) -> PerfProtocolConfig {
+        PerfProtocolConfig {}
+    }
+}
 
-#[derive(Default)]
 pub struct PerfHandler {
+    config: PerfConfig,
+    remote_peer_id: PeerId,
+    /// Whether the remote is allowed to start perf runs, see
+    /// [`PerfConfig::allowed_peers`].
+    allowed: bool,
+    /// Limits the perf runs started by the remote, shared with all other
+    /// connections.
+    limiter: Arc<RunLimiter>,
     outbox: Vec<
         ConnectionHandlerEvent<
             <Self as ConnectionHandler>::OutboundProtocol,
@@ -32,51 +67,219 @@ pub struct PerfHandler {
             <Self as ConnectionHandler>::Error,
         >,
     >,
-    perf_runs:
-        FuturesUnordered<
-            PerfRun<
-                <<Self as ConnectionHandler>::InboundProtocol as InboundUpgrade<
-                    NegotiatedSubstream,
-                >>::Output,
-                <<Self as ConnectionHandler>::OutboundProtocol as OutboundUpgrade<
-                    NegotiatedSubstream,
-                >>::Output,
-            >,
-        >,
+    perf_runs: FuturesUnordered<PerfRun<NegotiatedSubstream, NegotiatedSubstream>>,
+    /// Keep-alive once no run is pending, i.e. [`PerfConfig::connection_idle_timeout`]
+    /// after the connection was established or the last run finished.
+    keep_alive: KeepAlive,
+    /// Parent of the spans of all runs on this connection.
+    span: tracing::Span,
+}
+
+impl PerfHandler {
+    pub fn new(config: PerfConfig, remote_peer_id: PeerId, limiter: Arc<RunLimiter>) -> Self {
+        PerfHandler {
+            span: tracing::debug_span!("perf_handler", peer = %remote_peer_id),
+            allowed: config.is_allowed(&remote_peer_id),
+            keep_alive: KeepAlive::Until(Instant::now() + config.connection_idle_timeout),
+            config,
+            remote_peer_id,
+            limiter,
+            outbox: Default::default(),
+            perf_runs: Default::default(),
+        }
+    }
+}
+
+/// Measurements of a single perf run.
+#[derive(Debug, Clone)]
+pub struct RunStats {
+    pub duration: Duration,
+    pub transfered: usize,
+    /// Time the sender kept sending past the configured run duration. `None`
+    /// on the receiving side.
+    pub overshoot: Option<Duration>,
+    /// Time the sender spent waiting on the substream. `None` on the
+    /// receiving side.
+    pub write_timing: Option<WriteTiming>,
+}
+
+impl RunStats {
+    /// Throughput in bit/s.
+    pub fn throughput(&self) -> f64 {
+        self.transfered as f64 * 8.0 / self.duration.as_secs_f64()
+    }
+}
+
+/// Direction of a perf run from the local node's point of view.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Role {
+    Sender,
+    Receiver,
+}
+
+/// Description of a perf run, known when the run starts.
+#[derive(Debug, Clone)]
+pub struct RunInfo {
+    /// Set if the run was started by the local node.
+    pub id: Option<RunId>,
+    pub role: Role,
+    /// Negotiated protocol name.
+    pub protocol: String,
+    /// Parameters the run was started with. `None` if started by the remote.
+    pub params: Option<RunParams>,
+    pub started_at: SystemTime,
+    /// Resources used by the process when the run started, if available.
+    pub usage_at_start: Option<ResourceUsage>,
+}
+
+/// Caps enforced by the receiver on runs started by the remote, see
+/// [`PerfConfig::max_inbound_run_duration`],
+/// [`PerfConfig::max_inbound_run_bytes`] and
+/// [`PerfConfig::inbound_idle_timeout`].
+#[derive(Default)]
+struct ReceiverLimits {
+    max_duration: Option<Duration>,
+    max_bytes: Option<usize>,
+    idle_timeout: Option<Duration>,
 }
 
-enum PerfRun<I, O> {
+impl From<&PerfConfig> for ReceiverLimits {
+    fn from(config: &PerfConfig) -> Self {
+        ReceiverLimits {
+            max_duration: config.max_inbound_run_duration,
+            max_bytes: config.max_inbound_run_bytes,
+            idle_timeout: config.inbound_idle_timeout,
+        }
+    }
+}
+
+struct PerfRun<I, O> {
+    info: RunInfo,
+    /// Slot of a run started by the remote, freed once the run is dropped.
+    _permit: Option<RunPermit>,
+    clock: Arc<dyn Clock>,
+    run_duration: Duration,
+    /// Timer firing at the end of the run on the sending side, making sure the
+    /// run ends on time even when the substream is back-pressured. On the
+    /// receiving side, firing at the maximum run duration, if any.
+    deadline: Option<BoxFuture<'static, ()>>,
+    span: tracing::Span,
+    /// Child of `span` covering the current [`PerfRunState`], e.g. to tell
+    /// the time spent sending apart from the time spent closing.
+    state_span: tracing::Span,
+    limits: ReceiverLimits,
+    /// Timer firing at the earliest point in time the receiving side might
+    /// not have read any data for [`ReceiverLimits::idle_timeout`]. Re-armed
+    /// from `last_read` when firing, instead of on each read.
+    idle_timer: Option<BoxFuture<'static, ()>>,
+    /// Time the receiving side last read data, or the run started.
+    last_read: Instant,
+    write_timing: WriteTiming,
+    /// Set while the sending side's `poll_write` is pending.
+    write_pending_since: Option<Instant>,
+    state: PerfRunState<I, O>,
+}
+
+enum PerfRunState<I, O> {
     Running {
-        start: Option<std::time::Instant>,
+        start: Option<Instant>,
         transfered: usize,
         substream: PerfRunStream<I, O>,
     },
     ClosingWriter {
         duration: Duration,
         transfered: usize,
+        /// Real time the run entered this state.
+        closing_since: Instant,
+        /// Time it took to flush the substream, once flushed.
+        flushed: Option<Duration>,
         substream: O,
     },
-    Done {
-        duration: std::time::Duration,
-        transfered: usize,
+    /// The receiving side sending the [`AbortReason`] to the sender before
+    /// failing the run.
+    Aborting {
+        reason: AbortReason,
+        /// Whether the reason was written.
+        written: bool,
+        substream: I,
     },
+    Done(RunStats),
     Poisoned,
 }
 
 impl<I, O> PerfRun<I, O> {
-    fn new(substream: PerfRunStream<I, O>) -> Self {
-        PerfRun::Running {
-            start: None,
-            transfered: 0,
-            substream,
+    fn new(
+        info: RunInfo,
+        permit: Option<RunPermit>,
+        substream: PerfRunStream<I, O>,
+        clock: Arc<dyn Clock>,
+        run_duration: Duration,
+        limits: ReceiverLimits,
+        parent: &tracing::Span,
+    ) -> Self {
+        let span = tracing::debug_span!(
+            parent: parent,
+            "perf_run",
+            run = info.id.map(tracing::field::display),
+            role = ?info.role,
+            protocol = %info.protocol,
+        );
+        let state_span = tracing::debug_span!(parent: &span, "running");
+        tracing::debug!(parent: &state_span, "Starting perf run.");
+
+        let last_read = clock.now();
+        let idle_timer = limits
+            .idle_timeout
+            .map(|timeout| clock.sleep_until(last_read + timeout));
+
+        PerfRun {
+            info,
+            _permit: permit,
+            clock,
+            run_duration,
+            deadline: None,
+            span,
+            state_span,
+            limits,
+            idle_timer,
+            last_read,
+            write_timing: WriteTiming::default(),
+            write_pending_since: None,
+            state: PerfRunState::Running {
+                start: None,
+                transfered: 0,
+                substream,
+            },
         }
     }
 }
 
+/// Reads from the sending side's substream, returning the reason once the
+/// receiver aborted the run, or an error once it closed or reset the
+/// substream.
+fn poll_receiver_closed<O: AsyncRead + Unpin>(
+    substream: &mut O,
+    cx: &mut Context,
+) -> Poll<io::Result<AbortReason>> {
+    let mut buf = [0; 1];
+    match Pin::new(substream).poll_read(cx, &mut buf) {
+        Poll::Ready(Ok(0)) => Poll::Ready(Err(io::Error::new(
+            io::ErrorKind::ConnectionAborted,
+            "Receiver closed the substream.",
+        ))),
+        Poll::Ready(Ok(_)) => Poll::Ready(AbortReason::from_byte(buf[0]).ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidData, "Unexpected data from receiver.")
+        })),
+        Poll::Ready(Err(e)) => Poll::Ready(Err(e)),
+        Poll::Pending => Poll::Pending,
+    }
+}
+
 enum PerfRunStream<I, O> {
     // Receiver + void buffer.
     Receiver(I, Vec<u8>),
-    Sender(O),
+    // Sender + payload.
+    Sender(O, PayloadBuffer),
 }
 
 impl<I, O> Unpin for PerfRun<I, O> {}
@@ -86,121 +289,345 @@ where
     I: AsyncRead + AsyncWrite + Unpin,
     O: AsyncRead + AsyncWrite + Unpin,
 {
-    type Output = (Duration, usize);
+    type Output = (RunInfo, io::Result<RunStats>);
 
     fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        let poll = self.as_mut().poll_run(cx);
+
+        if let Poll::Ready((_, result)) = &poll {
+            match result {
+                Ok(stats) => tracing::debug!(
+                    parent: &self.span,
+                    transfered = stats.transfered,
+                    duration = ?stats.duration,
+                    overshoot = ?stats.overshoot,
+                    "Perf run done."
+                ),
+                Err(error) => tracing::debug!(
+                    parent: &self.span,
+                    %error,
+                    "Perf run failed."
+                ),
+            }
+        }
+
+        poll
+    }
+}
+
+impl<I, O> PerfRun<I, O>
+where
+    I: AsyncRead + AsyncWrite + Unpin,
+    O: AsyncRead + AsyncWrite + Unpin,
+{
+    /// Returns whether the receiving side did not read any data for
+    /// [`ReceiverLimits::idle_timeout`], re-arming the timer otherwise.
+    fn poll_idle_timeout(&mut self, cx: &mut Context) -> bool {
+        let timeout = match self.limits.idle_timeout {
+            Some(timeout) => timeout,
+            None => return false,
+        };
+
+        while let Some(timer) = self.idle_timer.as_mut() {
+            if timer.poll_unpin(cx).is_pending() {
+                return false;
+            }
+
+            let idle_deadline = self.last_read + timeout;
+            if self.clock.now() >= idle_deadline {
+                self.idle_timer = None;
+                return true;
+            }
+            self.idle_timer = Some(self.clock.sleep_until(idle_deadline));
+        }
+
+        false
+    }
+
+    /// Aborts a run on the receiving side for exceeding one of its
+    /// [`ReceiverLimits`].
+    fn abort(&mut self, reason: AbortReason, substream: I) {
+        tracing::debug!(?reason, "Aborting perf run.");
+        self.deadline = None;
+        self.idle_timer = None;
+        self.state_span = tracing::debug_span!(parent: &self.span, "aborting");
+        self.state = PerfRunState::Aborting {
+            reason,
+            written: false,
+            substream,
+        };
+    }
+
+    fn poll_run(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context,
+    ) -> Poll<(RunInfo, io::Result<RunStats>)> {
+        let this = &mut *self;
+
         loop {
-            match std::mem::replace(&mut *self, PerfRun::Poisoned) {
-                PerfRun::Running {
+            let state_span = this.state_span.clone();
+            let _entered = state_span.enter();
+
+            match std::mem::replace(&mut this.state, PerfRunState::Poisoned) {
+                PerfRunState::Running {
                     mut start,
                     transfered,
-                    substream: PerfRunStream::Sender(mut substream),
+                    substream: PerfRunStream::Sender(mut substream, mut payload),
                 } => {
-                    match start {
-                        Some(start) => {
-                            if start.elapsed() >= Duration::from_secs(10) {
-                                *self = PerfRun::ClosingWriter {
-                                    duration: start.elapsed(),
-                                    transfered,
-                                    substream,
-                                };
+                    let now = this.clock.now();
+                    let started = match start {
+                        Some(start) => start,
+                        None => {
+                            this.deadline = Some(this.clock.sleep_until(now + this.run_duration));
+                            start = Some(now);
+                            now
+                        }
+                    };
 
-                                continue;
-                            }
+                    let deadline_passed = match this.deadline.as_mut() {
+                        Some(deadline) => deadline.poll_unpin(cx).is_ready(),
+                        None => false,
+                    };
+                    if deadline_passed || now - started >= this.run_duration {
+                        // The timer might fire slightly ahead of `now` being
+                        // past the deadline.
+                        let now = if deadline_passed {
+                            this.clock.now()
+                        } else {
+                            now
+                        };
+                        this.deadline = None;
+                        // A write cut short by the end of the run.
+                        if let Some(since) = this.write_pending_since.take() {
+                            this.write_timing.stalls.record(now - since);
                         }
-                        None => start = Some(Instant::now()),
+                        tracing::debug!(transfered, "Run duration passed, closing substream.");
+                        this.state_span =
+                            tracing::debug_span!(parent: &this.span, "closing_writer");
+                        this.state = PerfRunState::ClosingWriter {
+                            duration: now - started,
+                            transfered,
+                            closing_since: now,
+                            flushed: None,
+                            substream,
+                        };
+
+                        continue;
                     }
 
-                    match Pin::new(&mut substream).poll_write(cx, MSG) {
+                    match Pin::new(&mut substream).poll_write(cx, payload.next()) {
                         Poll::Ready(Ok(n)) => {
-                            *self = PerfRun::Running {
+                            payload.advance(n);
+                            if let Some(since) = this.write_pending_since.take() {
+                                this.write_timing.stalls.record(now - since);
+                            }
+                            this.state = PerfRunState::Running {
                                 start,
                                 transfered: transfered + n,
-                                substream: PerfRunStream::Sender(substream),
+                                substream: PerfRunStream::Sender(substream, payload),
                             };
                         }
-                        Poll::Ready(Err(e)) => panic!("Unexpected error {:?}", e),
+                        Poll::Ready(Err(e)) => {
+                            // A receiver aborting the run resets the substream
+                            // right after sending the reason.
+                            let e = match poll_receiver_closed(&mut substream, cx) {
+                                Poll::Ready(Ok(reason)) => reason.sender_error(),
+                                _ => e,
+                            };
+                            return Poll::Ready((this.info.clone(), Err(e)));
+                        }
                         Poll::Pending => {
-                            *self = PerfRun::Running {
+                            // The receiver only writes when aborting the run,
+                            // thus only check whether it did, or closed or
+                            // reset the substream, once back-pressured, e.g. by
+                            // a receiver that stopped reading. Writes on a
+                            // reset substream fail anyway.
+                            if let Poll::Ready(result) = poll_receiver_closed(&mut substream, cx) {
+                                let e = match result {
+                                    Ok(reason) => reason.sender_error(),
+                                    Err(e) => e,
+                                };
+                                return Poll::Ready((this.info.clone(), Err(e)));
+                            }
+                            this.write_pending_since.get_or_insert(now);
+                            this.state = PerfRunState::Running {
                                 start,
                                 transfered,
-                                substream: PerfRunStream::Sender(substream),
+                                substream: PerfRunStream::Sender(substream, payload),
                             };
                             return Poll::Pending;
                         }
                     }
                 }
-                PerfRun::Running {
-                    mut start,
+                PerfRunState::Running {
+                    start,
                     transfered,
                     substream: PerfRunStream::Receiver(mut substream, mut void_buf),
-                } => match Pin::new(&mut substream).poll_read(cx, &mut void_buf) {
-                    Poll::Ready(Ok(n)) => {
-                        start = start.or_else(|| Some(Instant::now()));
-                        if n == 0 {
-                            *self = PerfRun::Done {
-                                duration: start.unwrap().elapsed(),
+                } => {
+                    let violation = if matches!(
+                        this.deadline.as_mut().map(|d| d.poll_unpin(cx)),
+                        Some(Poll::Ready(()))
+                    ) {
+                        Some(AbortReason::MaxDuration)
+                    } else if this.poll_idle_timeout(cx) {
+                        Some(AbortReason::IdleTimeout)
+                    } else {
+                        None
+                    };
+                    if let Some(reason) = violation {
+                        this.abort(reason, substream);
+                        continue;
+                    }
+
+                    match Pin::new(&mut substream).poll_read(cx, &mut void_buf) {
+                        Poll::Ready(Ok(n)) => {
+                            let now = this.clock.now();
+                            let start = match start {
+                                Some(start) => start,
+                                None => {
+                                    tracing::debug!("Received first bytes.");
+                                    if let Some(max) = this.limits.max_duration {
+                                        this.deadline = Some(this.clock.sleep_until(now + max));
+                                    }
+                                    now
+                                }
+                            };
+                            if n == 0 {
+                                tracing::debug!(transfered, "Sender closed the substream.");
+                                this.state_span = tracing::Span::none();
+                                this.state = PerfRunState::Done(RunStats {
+                                    duration: now - start,
+                                    transfered,
+                                    overshoot: None,
+                                    write_timing: None,
+                                });
+                                continue;
+                            }
+
+                            let transfered = transfered + n;
+                            if matches!(this.limits.max_bytes, Some(max) if transfered > max) {
+                                this.abort(AbortReason::MaxBytes, substream);
+                                continue;
+                            }
+                            this.last_read = now;
+
+                            this.state = PerfRunState::Running {
+                                start: Some(start),
                                 transfered,
+                                substream: PerfRunStream::Receiver(substream, void_buf),
                             };
-                        } else {
-                            *self = PerfRun::Running {
+                        }
+                        Poll::Ready(Err(e)) => return Poll::Ready((this.info.clone(), Err(e))),
+                        Poll::Pending => {
+                            this.state = PerfRunState::Running {
                                 start,
-                                transfered: transfered + n,
+                                transfered,
                                 substream: PerfRunStream::Receiver(substream, void_buf),
                             };
+                            return Poll::Pending;
                         }
                     }
-                    Poll::Ready(Err(e)) => panic!("Unexpected error {:?}", e),
-                    Poll::Pending => {
-                        *self = PerfRun::Running {
-                            start,
-                            transfered,
-                            substream: PerfRunStream::Receiver(substream, void_buf),
-                        };
-                        return Poll::Pending;
+                }
+                PerfRunState::Aborting {
+                    reason,
+                    mut written,
+                    mut substream,
+                } => {
+                    // Telling the sender is best effort, the run fails with
+                    // the reason either way.
+                    if !written {
+                        match Pin::new(&mut substream).poll_write(cx, &[reason.to_byte()]) {
+                            Poll::Ready(Ok(n)) if n > 0 => written = true,
+                            Poll::Ready(_) => {
+                                return Poll::Ready((this.info.clone(), Err(reason.into())))
+                            }
+                            Poll::Pending => {
+                                this.state = PerfRunState::Aborting {
+                                    reason,
+                                    written,
+                                    substream,
+                                };
+                                return Poll::Pending;
+                            }
+                        }
+                    }
+
+                    match Pin::new(&mut substream).poll_close(cx) {
+                        // Dropping the substream resets it, failing further
+                        // writes of the sender.
+                        Poll::Ready(_) => {
+                            return Poll::Ready((this.info.clone(), Err(reason.into())))
+                        }
+                        Poll::Pending => {
+                            this.state = PerfRunState::Aborting {
+                                reason,
+                                written,
+                                substream,
+                            };
+                            return Poll::Pending;
+                        }
                     }
-                },
-                PerfRun::ClosingWriter {
+                }
+                PerfRunState::ClosingWriter {
                     duration,
                     transfered,
+                    closing_since,
+                    mut flushed,
                     mut substream,
-                } => match Pin::new(&mut substream).poll_flush(cx) {
-                    Poll::Ready(Ok(())) => match Pin::new(&mut substream).poll_close(cx) {
+                } => {
+                    if flushed.is_none() {
+                        match Pin::new(&mut substream).poll_flush(cx) {
+                            Poll::Ready(Ok(())) => {
+                                tracing::debug!("Substream flushed.");
+                                flushed = Some(this.clock.now() - closing_since);
+                            }
+                            Poll::Ready(Err(e)) => return Poll::Ready((this.info.clone(), Err(e))),
+                            Poll::Pending => {
+                                this.state = PerfRunState::ClosingWriter {
+                                    duration,
+                                    transfered,
+                                    closing_since,
+                                    flushed,
+                                    substream,
+                                };
+                                return Poll::Pending;
+                            }
+                        }
+                    }
+
+                    match Pin::new(&mut substream).poll_close(cx) {
                         Poll::Ready(Ok(())) => {
-                            drop(substream);
-                            std::thread::sleep(Duration::from_secs(1));
-                            *self = PerfRun::Done {
+                            tracing::debug!("Substream closed.");
+                            this.state_span = tracing::Span::none();
+
+                            let mut write_timing = std::mem::take(&mut this.write_timing);
+                            write_timing.flush = flushed.unwrap_or_default();
+                            write_timing.close = (this.clock.now() - closing_since)
+                                .saturating_sub(write_timing.flush);
+                            this.state = PerfRunState::Done(RunStats {
                                 duration,
                                 transfered,
-                            };
+                                overshoot: Some(duration.saturating_sub(this.run_duration)),
+                                write_timing: Some(write_timing),
+                            });
                         }
-                        Poll::Ready(Err(_)) => panic!("Failed to close connection"),
+                        Poll::Ready(Err(e)) => return Poll::Ready((this.info.clone(), Err(e))),
                         Poll::Pending => {
-                            *self = PerfRun::ClosingWriter {
+                            this.state = PerfRunState::ClosingWriter {
                                 duration,
                                 transfered,
+                                closing_since,
+                                flushed,
                                 substream,
                             };
                             return Poll::Pending;
                         }
-                    },
-                    Poll::Ready(Err(_)) => panic!("Got error while closing substream"),
-                    Poll::Pending => {
-                        *self = PerfRun::ClosingWriter {
-                            duration,
-                            transfered,
-                            substream,
-                        };
-                        return Poll::Pending;
                     }
-                },
-                PerfRun::Done {
-                    duration,
-                    transfered,
-                } => {
-                    return Poll::Ready((duration, transfered));
                 }
-                PerfRun::Poisoned => panic!("PerfRun::Poisoned"),
+                PerfRunState::Done(stats) => {
+                    return Poll::Ready((this.info.clone(), Ok(stats)));
+                }
+                PerfRunState::Poisoned => panic!("PerfRun::Poisoned"),
             }
         }
     }
@@ -208,12 +635,24 @@ where
 
 #[derive(Debug, Clone)]
 pub enum PerfHandlerIn {
-    StartPerf,
+    StartPerf(RunId, RunParams),
+}
+
+/// A perf run that finished successfully.
+#[derive(Debug)]
+pub struct RunDone {
+    pub info: RunInfo,
+    pub stats: RunStats,
+    pub finished_at: SystemTime,
+    pub cpu: Option<CpuUsage>,
+    pub memory: Option<MemoryUsage>,
 }
 
 #[derive(Debug)]
 pub enum PerfHandlerOut {
-    PerfRunDone(Duration, usize),
+    PerfRunDone(Box<RunDone>),
+    PerfRunFailed { id: Option<RunId>, error: io::Error },
+    InboundRunRejected(RejectReason),
 }
 
 impl ConnectionHandler for PerfHandler {
@@ -229,7 +668,7 @@ impl ConnectionHandler for PerfHandler {
     /// The type of additional information returned from `listen_protocol`.
     type InboundOpenInfo = ();
     /// The type of additional information passed to an `OutboundSubstreamRequest`.
-    type OutboundOpenInfo = ();
+    type OutboundOpenInfo = (RunId, RunParams);
 
     /// The [`InboundUpgrade`](libp2p_core::upgrade::InboundUpgrade) to apply on inbound
     /// substreams to negotiate the desired protocols.
@@ -245,13 +684,43 @@ impl ConnectionHandler for PerfHandler {
     /// Injects the output of a successful upgrade on a new inbound substream.
     fn inject_fully_negotiated_inbound(
         &mut self,
-        substream: <Self::InboundProtocol as InboundUpgrade<NegotiatedSubstream>>::Output,
+        (substream, protocol): <Self::InboundProtocol as InboundUpgrade<NegotiatedSubstream>>::Output,
         _info: Self::InboundOpenInfo,
     ) {
-        self.perf_runs.push(PerfRun::new(PerfRunStream::Receiver(
-            substream,
-            vec![0; BUFFER_SIZE],
-        )));
+        // Dropping the substream resets it, failing the run on the remote.
+        let permit = if self.allowed {
+            self.limiter.try_acquire(self.remote_peer_id)
+        } else {
+            Err(RejectReason::NotAllowed)
+        };
+        let permit = match permit {
+            Ok(permit) => permit,
+            Err(reason) => {
+                tracing::debug!(parent: &self.span, %reason, "Rejecting inbound perf run.");
+                self.outbox.push(ConnectionHandlerEvent::Custom(
+                    PerfHandlerOut::InboundRunRejected(reason),
+                ));
+                return;
+            }
+        };
+
+        let info = RunInfo {
+            id: None,
+            role: Role::Receiver,
+            protocol,
+            params: None,
+            started_at: SystemTime::now(),
+            usage_at_start: ResourceUsage::now().ok(),
+        };
+        self.perf_runs.push(PerfRun::new(
+            info,
+            Some(permit),
+            PerfRunStream::Receiver(substream, vec![0; self.config.buffer_size]),
+            self.config.clock.clone(),
+            self.config.run_duration,
+            ReceiverLimits::from(&self.config),
+            &self.span,
+        ));
     }
 
     /// Injects the output of a successful upgrade on a new outbound substream.
@@ -260,20 +729,39 @@ impl ConnectionHandler for PerfHandler {
     /// [`ConnectionHandlerEvent::OutboundSubstreamRequest`].
     fn inject_fully_negotiated_outbound(
         &mut self,
-        substream: <Self::OutboundProtocol as OutboundUpgrade<NegotiatedSubstream>>::Output,
-        _info: Self::OutboundOpenInfo,
+        (substream, protocol): <Self::OutboundProtocol as OutboundUpgrade<NegotiatedSubstream>>::Output,
+        (id, params): Self::OutboundOpenInfo,
     ) {
-        self.perf_runs
-            .push(PerfRun::new(PerfRunStream::Sender(substream)));
+        // Generated before the run starts, thus not affecting its throughput.
+        let payload = PayloadBuffer::new(&params.payload, params.buffer_size);
+        let run_duration = params.run_duration;
+        let info = RunInfo {
+            id: Some(id),
+            role: Role::Sender,
+            protocol,
+            params: Some(params),
+            started_at: SystemTime::now(),
+            usage_at_start: ResourceUsage::now().ok(),
+        };
+        self.perf_runs.push(PerfRun::new(
+            info,
+            None,
+            PerfRunStream::Sender(substream, payload),
+            self.config.clock.clone(),
+            run_duration,
+            ReceiverLimits::default(),
+            &self.span,
+        ));
     }
 
     /// Injects an event coming from the outside in the handler.
     fn inject_event(&mut self, event: Self::InEvent) {
         match event {
-            PerfHandlerIn::StartPerf => {
+            PerfHandlerIn::StartPerf(id, params) => {
+                tracing::debug!(parent: &self.span, run = %id, "Opening perf substream.");
                 self.outbox
                     .push(ConnectionHandlerEvent::OutboundSubstreamRequest {
-                        protocol: SubstreamProtocol::new(PerfProtocolConfig {}, ()),
+                        protocol: SubstreamProtocol::new(PerfProtocolConfig {}, (id, params)),
                     })
             }
         }
@@ -282,12 +770,34 @@ impl ConnectionHandler for PerfHandler {
     /// Indicates to the handler that upgrading a substream to the given protocol has failed.
     fn inject_dial_upgrade_error(
         &mut self,
-        _info: Self::OutboundOpenInfo,
+        (id, _): Self::OutboundOpenInfo,
         error: ConnectionHandlerUpgrErr<
             <Self::OutboundProtocol as OutboundUpgrade<NegotiatedSubstream>>::Error,
         >,
     ) {
-        panic!("{:?}", error);
+        tracing::debug!(
+            parent: &self.span,
+            run = %id,
+            %error,
+            "Failed to open perf substream."
+        );
+        self.outbox.push(ConnectionHandlerEvent::Custom(
+            PerfHandlerOut::PerfRunFailed {
+                id: Some(id),
+                error: io::Error::other(error.to_string()),
+            },
+        ));
+    }
+
+    /// Indicates to the handler that upgrading an inbound substream to the given protocol has failed.
+    fn inject_listen_upgrade_error(
+        &mut self,
+        _info: Self::InboundOpenInfo,
+        error: ConnectionHandlerUpgrErr<
+            <Self::InboundProtocol as InboundUpgrade<NegotiatedSubstream>>::Error,
+        >,
+    ) {
+        tracing::debug!(parent: &self.span, %error, "Inbound substream failed.");
     }
 
     /// Returns until when the connection should be kept alive.
@@ -311,7 +821,11 @@ impl ConnectionHandler for PerfHandler {
     /// > connection may be closed for reasons outside of the control
     /// > of the handler.
     fn connection_keep_alive(&self) -> KeepAlive {
-        KeepAlive::Yes
+        if self.perf_runs.is_empty() && self.outbox.is_empty() {
+            self.keep_alive
+        } else {
+            KeepAlive::Yes
+        }
     }
 
     fn poll(
@@ -329,12 +843,38 @@ impl ConnectionHandler for PerfHandler {
             return Poll::Ready(event);
         }
 
-        match self.perf_runs.poll_next_unpin(cx) {
-            Poll::Ready(Some((duration, transfered))) => {
+        let result = self.perf_runs.poll_next_unpin(cx);
+        if let Poll::Ready(Some(_)) = result {
+            self.keep_alive =
+                KeepAlive::Until(Instant::now() + self.config.connection_idle_timeout);
+        }
+
+        match result {
+            Poll::Ready(Some((info, Ok(stats)))) => {
+                let finished_at = SystemTime::now();
+                let usage = info.usage_at_start.zip(ResourceUsage::now().ok());
+                let cpu = usage.map(|(start, end)| {
+                    let wall = finished_at
+                        .duration_since(info.started_at)
+                        .unwrap_or_default();
+                    end.cpu_since(&start, wall)
+                });
+                let memory = usage.map(|(start, end)| end.memory_since(&start));
                 return Poll::Ready(ConnectionHandlerEvent::Custom(PerfHandlerOut::PerfRunDone(
-                    duration, transfered,
+                    Box::new(RunDone {
+                        info,
+                        stats,
+                        finished_at,
+                        cpu,
+                        memory,
+                    }),
                 )));
             }
+            Poll::Ready(Some((info, Err(error)))) => {
+                return Poll::Ready(ConnectionHandlerEvent::Custom(
+                    PerfHandlerOut::PerfRunFailed { id: info.id, error },
+                ));
+            }
             // No Futures within `self.perf_runs`.
             Poll::Ready(None) => {}
             Poll::Pending => {}
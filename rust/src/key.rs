@@ -0,0 +1,499 @@
+use libp2p::identity::{ecdsa, ed25519, secp256k1, Keypair};
+use std::io;
+use std::path::Path;
+
+/// Identity key type of a freshly generated [`Keypair`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeyType {
+    Ed25519,
+    Secp256k1,
+    Ecdsa,
+}
+
+impl std::str::FromStr for KeyType {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "ed25519" => Ok(Self::Ed25519),
+            "secp256k1" => Ok(Self::Secp256k1),
+            "ecdsa" => Ok(Self::Ecdsa),
+            _ => Err("Expected one of 'ed25519', 'secp256k1' or 'ecdsa'.".to_string()),
+        }
+    }
+}
+
+impl std::fmt::Display for KeyType {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+pub fn generate_keypair(key_type: KeyType) -> Keypair {
+    match key_type {
+        KeyType::Ed25519 => Keypair::generate_ed25519(),
+        KeyType::Secp256k1 => Keypair::generate_secp256k1(),
+        KeyType::Ecdsa => Keypair::generate_ecdsa(),
+    }
+}
+
+/// Reads a [`Keypair`] from a file.
+///
+/// Supported encodings are:
+///
+/// - The libp2p protobuf encoding of a private key, as used by go-libp2p, for
+///   Ed25519, Secp256k1 and ECDSA keys.
+///
+/// - PEM, either PKCS#8 (`PRIVATE KEY`) for Ed25519, Secp256k1, ECDSA and RSA
+///   keys, or SEC1 (`EC PRIVATE KEY`) for Secp256k1 and ECDSA keys, e.g. as
+///   generated by `openssl genpkey` or `openssl ecparam -genkey`.
+///
+/// - DER encoded PKCS#8, e.g. an RSA key converted via `openssl pkcs8 -topk8
+///   -outform DER`.
+pub fn read_keypair(path: &Path) -> io::Result<Keypair> {
+    let bytes = std::fs::read(path)?;
+    decode_keypair(bytes).map_err(|e| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("Failed to decode key {}: {}", path.display(), e),
+        )
+    })
+}
+
+/// Writes a [`Keypair`] to a new file using the libp2p protobuf encoding,
+/// readable by [`read_keypair`] and go-libp2p. Fails if the file exists.
+pub fn write_keypair(path: &Path, keypair: &Keypair) -> io::Result<()> {
+    let bytes =
+        encode_protobuf(keypair).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+
+    io::Write::write_all(&mut options.open(path)?, &bytes)
+}
+
+fn decode_keypair(mut bytes: Vec<u8>) -> Result<Keypair, String> {
+    if bytes.starts_with(b"-----BEGIN ") {
+        let pem = String::from_utf8(bytes).map_err(|e| e.to_string())?;
+        return decode_pem(&pem);
+    }
+
+    // libp2p decodes the go-libp2p protobuf encoding of Ed25519 keys, i.e.
+    // the secret key followed by the public key, but no other key types.
+    if let Ok(keypair) = Keypair::from_protobuf_encoding(&bytes) {
+        return Ok(keypair);
+    }
+    if let Ok(keypair) = decode_protobuf(&bytes) {
+        return Ok(keypair);
+    }
+
+    decode_pkcs8(&mut bytes)
+}
+
+fn decode_pem(pem: &str) -> Result<Keypair, String> {
+    let mut lines = pem.lines();
+
+    while let Some(line) = lines.next() {
+        let label = match line
+            .trim()
+            .strip_prefix("-----BEGIN ")
+            .and_then(|l| l.strip_suffix("-----"))
+        {
+            Some(label) => label.to_string(),
+            None => continue,
+        };
+
+        let end = format!("-----END {}-----", label);
+        let body: String = lines
+            .by_ref()
+            .take_while(|l| l.trim() != end)
+            .map(str::trim)
+            .collect();
+
+        match label.as_str() {
+            // Emitted by `openssl ecparam -genkey` in front of the key.
+            "EC PARAMETERS" => continue,
+            "PRIVATE KEY" => {
+                let mut der = base64::decode(&body).map_err(|e| e.to_string())?;
+                return decode_pkcs8(&mut der);
+            }
+            "EC PRIVATE KEY" => {
+                let mut der = base64::decode(&body).map_err(|e| e.to_string())?;
+                return decode_sec1(&mut der, None);
+            }
+            label => return Err(format!("Unsupported PEM label '{}'.", label)),
+        }
+    }
+
+    Err("No private key found in PEM file.".to_string())
+}
+
+// Key types of the libp2p protobuf `PrivateKey` message.
+//
+// https://github.com/libp2p/specs/blob/master/peer-ids/peer-ids.md#keys
+const PROTOBUF_KEY_TYPE_ED25519: u64 = 1;
+const PROTOBUF_KEY_TYPE_SECP256K1: u64 = 2;
+const PROTOBUF_KEY_TYPE_ECDSA: u64 = 3;
+
+fn decode_protobuf(bytes: &[u8]) -> Result<Keypair, String> {
+    let mut key_type = None;
+    let mut data = None;
+
+    let mut rest = bytes;
+    while !rest.is_empty() {
+        let (tag, r) = read_varint(rest)?;
+        rest = r;
+        match tag {
+            // Field 1, varint.
+            0x08 => {
+                let (t, r) = read_varint(rest)?;
+                key_type = Some(t);
+                rest = r;
+            }
+            // Field 2, length delimited.
+            0x12 => {
+                let (len, r) = read_varint(rest)?;
+                let len = len as usize;
+                if r.len() < len {
+                    return Err("Truncated protobuf key data.".to_string());
+                }
+                data = Some(r[..len].to_vec());
+                rest = &r[len..];
+            }
+            _ => return Err(format!("Unexpected protobuf tag {}.", tag)),
+        }
+    }
+
+    let mut data = data.ok_or("Missing protobuf key data.")?;
+    match key_type.ok_or("Missing protobuf key type.")? {
+        // Other implementations than go-libp2p only encode the secret key.
+        PROTOBUF_KEY_TYPE_ED25519 => Ok(Keypair::Ed25519(
+            ed25519::SecretKey::from_bytes(&mut data)
+                .map_err(|e| e.to_string())?
+                .into(),
+        )),
+        PROTOBUF_KEY_TYPE_SECP256K1 => Ok(Keypair::Secp256k1(
+            secp256k1::SecretKey::from_bytes(&mut data)
+                .map_err(|e| e.to_string())?
+                .into(),
+        )),
+        PROTOBUF_KEY_TYPE_ECDSA => {
+            // go-libp2p encodes ECDSA keys as SEC1 DER, accept the raw scalar
+            // as well.
+            if data.len() == 32 {
+                Ok(Keypair::Ecdsa(
+                    ecdsa::SecretKey::from_bytes(&data)
+                        .map_err(|e| e.to_string())?
+                        .into(),
+                ))
+            } else {
+                decode_sec1(&mut data, Some(Curve::P256))
+            }
+        }
+        t => Err(format!("Unsupported protobuf key type {}.", t)),
+    }
+}
+
+fn encode_protobuf(keypair: &Keypair) -> Result<Vec<u8>, String> {
+    let (key_type, data) = match keypair {
+        Keypair::Ed25519(_) => return keypair.to_protobuf_encoding().map_err(|e| e.to_string()),
+        Keypair::Secp256k1(keypair) => (
+            PROTOBUF_KEY_TYPE_SECP256K1,
+            keypair.secret().to_bytes().to_vec(),
+        ),
+        Keypair::Ecdsa(keypair) => (
+            PROTOBUF_KEY_TYPE_ECDSA,
+            encode_sec1_p256(&keypair.secret().to_bytes()),
+        ),
+        Keypair::Rsa(_) => return Err("Encoding RSA keys is not supported.".to_string()),
+    };
+
+    let mut bytes = vec![0x08];
+    write_varint(&mut bytes, key_type);
+    bytes.push(0x12);
+    write_varint(&mut bytes, data.len() as u64);
+    bytes.extend_from_slice(&data);
+    Ok(bytes)
+}
+
+fn write_varint(bytes: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        bytes.push((value as u8 & 0x7f) | 0x80);
+        value >>= 7;
+    }
+    bytes.push(value as u8);
+}
+
+fn read_varint(bytes: &[u8]) -> Result<(u64, &[u8]), String> {
+    let mut value = 0u64;
+    for (i, b) in bytes.iter().enumerate().take(10) {
+        value |= u64::from(b & 0x7f) << (7 * i);
+        if b & 0x80 == 0 {
+            return Ok((value, &bytes[i + 1..]));
+        }
+    }
+    Err("Invalid varint.".to_string())
+}
+
+// DER encoded object identifiers.
+const OID_RSA_ENCRYPTION: &[u8] = &[0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x01, 0x01];
+const OID_ED25519: &[u8] = &[0x2b, 0x65, 0x70];
+const OID_EC_PUBLIC_KEY: &[u8] = &[0x2a, 0x86, 0x48, 0xce, 0x3d, 0x02, 0x01];
+const OID_PRIME256V1: &[u8] = &[0x2a, 0x86, 0x48, 0xce, 0x3d, 0x03, 0x01, 0x07];
+const OID_SECP256K1: &[u8] = &[0x2b, 0x81, 0x04, 0x00, 0x0a];
+
+const DER_INTEGER: u8 = 0x02;
+const DER_OCTET_STRING: u8 = 0x04;
+const DER_OID: u8 = 0x06;
+const DER_SEQUENCE: u8 = 0x30;
+const DER_CONTEXT_0: u8 = 0xa0;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Curve {
+    P256,
+    Secp256k1,
+}
+
+impl Curve {
+    fn from_oid(oid: &[u8]) -> Result<Self, String> {
+        match oid {
+            OID_PRIME256V1 => Ok(Curve::P256),
+            OID_SECP256K1 => Ok(Curve::Secp256k1),
+            _ => Err(format!("Unsupported elliptic curve {:x?}.", oid)),
+        }
+    }
+}
+
+/// Decodes a PKCS#8 `PrivateKeyInfo` as defined in RFC 5208.
+fn decode_pkcs8(der: &mut [u8]) -> Result<Keypair, String> {
+    let (algorithm, parameters, mut private_key) = {
+        let (info, _) = read_der(der, DER_SEQUENCE)?;
+        let (_version, rest) = read_der(info, DER_INTEGER)?;
+        let (algorithm_identifier, rest) = read_der(rest, DER_SEQUENCE)?;
+        let (private_key, _) = read_der(rest, DER_OCTET_STRING)?;
+        let (algorithm, parameters) = read_der(algorithm_identifier, DER_OID)?;
+        (
+            algorithm.to_vec(),
+            parameters.to_vec(),
+            private_key.to_vec(),
+        )
+    };
+
+    match algorithm.as_slice() {
+        OID_RSA_ENCRYPTION => Keypair::rsa_from_pkcs8(der).map_err(|e| e.to_string()),
+        OID_ED25519 => {
+            let (secret, _) = read_der(&private_key, DER_OCTET_STRING)?;
+            Ok(Keypair::Ed25519(
+                ed25519::SecretKey::from_bytes(secret.to_vec())
+                    .map_err(|e| e.to_string())?
+                    .into(),
+            ))
+        }
+        OID_EC_PUBLIC_KEY => {
+            let (curve, _) = read_der(&parameters, DER_OID)?;
+            decode_sec1(&mut private_key, Some(Curve::from_oid(curve)?))
+        }
+        _ => Err(format!("Unsupported PKCS#8 algorithm {:x?}.", algorithm)),
+    }
+}
+
+/// Decodes a SEC1 `ECPrivateKey` as defined in RFC 5915. The curve is taken
+/// from the structure itself if not provided by the enclosing structure.
+fn decode_sec1(der: &mut [u8], curve: Option<Curve>) -> Result<Keypair, String> {
+    let (key, _) = read_der(der, DER_SEQUENCE)?;
+    let (_version, rest) = read_der(key, DER_INTEGER)?;
+    let (secret, rest) = read_der(rest, DER_OCTET_STRING)?;
+    let curve = match curve {
+        Some(curve) => curve,
+        None => {
+            let (parameters, _) = read_der(rest, DER_CONTEXT_0)?;
+            let (curve, _) = read_der(parameters, DER_OID)?;
+            Curve::from_oid(curve)?
+        }
+    };
+
+    match curve {
+        Curve::P256 => Ok(Keypair::Ecdsa(
+            ecdsa::SecretKey::from_bytes(secret)
+                .map_err(|e| e.to_string())?
+                .into(),
+        )),
+        Curve::Secp256k1 => Ok(Keypair::Secp256k1(
+            secp256k1::SecretKey::from_bytes(secret.to_vec())
+                .map_err(|e| e.to_string())?
+                .into(),
+        )),
+    }
+}
+
+/// Encodes a P-256 secret scalar as SEC1 `ECPrivateKey`, omitting the
+/// optional public key.
+fn encode_sec1_p256(secret: &[u8]) -> Vec<u8> {
+    let mut key = Vec::new();
+    write_der(&mut key, DER_INTEGER, &[0x01]);
+    write_der(&mut key, DER_OCTET_STRING, secret);
+    let mut curve = Vec::new();
+    write_der(&mut curve, DER_OID, OID_PRIME256V1);
+    write_der(&mut key, DER_CONTEXT_0, &curve);
+
+    let mut der = Vec::new();
+    write_der(&mut der, DER_SEQUENCE, &key);
+    der
+}
+
+/// Writes a single DER element. Only supports short form lengths, which is
+/// sufficient for the structures above.
+fn write_der(der: &mut Vec<u8>, tag: u8, content: &[u8]) {
+    assert!(content.len() < 0x80, "Unexpected DER content length.");
+    der.push(tag);
+    der.push(content.len() as u8);
+    der.extend_from_slice(content);
+}
+
+/// Reads a single DER element with the given tag, returning its content and
+/// the remaining input.
+fn read_der(der: &[u8], tag: u8) -> Result<(&[u8], &[u8]), String> {
+    match der.first() {
+        Some(t) if *t == tag => {}
+        Some(t) => return Err(format!("Expected DER tag {:#x}, got {:#x}.", tag, t)),
+        None => return Err("Unexpected end of DER input.".to_string()),
+    }
+
+    let (len, rest) = match der.get(1) {
+        Some(len) if len & 0x80 == 0 => (*len as usize, &der[2..]),
+        Some(len) => {
+            let num_bytes = (len & 0x7f) as usize;
+            if num_bytes == 0 || num_bytes > 4 || der.len() < 2 + num_bytes {
+                return Err("Invalid DER length.".to_string());
+            }
+            let len = der[2..2 + num_bytes]
+                .iter()
+                .fold(0usize, |acc, b| (acc << 8) | *b as usize);
+            (len, &der[2 + num_bytes..])
+        }
+        None => return Err("Unexpected end of DER input.".to_string()),
+    };
+
+    if rest.len() < len {
+        return Err("Truncated DER input.".to_string());
+    }
+
+    Ok((&rest[..len], &rest[len..]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use libp2p::PeerId;
+    use std::path::PathBuf;
+
+    fn fixture(name: &str) -> PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("tests/fixtures/keys")
+            .join(name)
+    }
+
+    fn peer_id(path: &Path) -> PeerId {
+        PeerId::from(read_keypair(path).unwrap().public())
+    }
+
+    fn temp_path() -> PathBuf {
+        std::env::temp_dir().join(format!("libp2p-perf-key-{}", rand::random::<u64>()))
+    }
+
+    #[test]
+    fn write_then_read_keypair() {
+        for key_type in [KeyType::Ed25519, KeyType::Secp256k1, KeyType::Ecdsa] {
+            let keypair = generate_keypair(key_type);
+            let path = temp_path();
+
+            write_keypair(&path, &keypair).unwrap();
+            let read = read_keypair(&path);
+            std::fs::remove_file(&path).unwrap();
+
+            assert_eq!(
+                PeerId::from(read.unwrap().public()),
+                PeerId::from(keypair.public()),
+                "{}",
+                key_type
+            );
+        }
+    }
+
+    #[test]
+    fn write_keypair_does_not_overwrite() {
+        let path = temp_path();
+        std::fs::write(&path, b"existing").unwrap();
+
+        let result = write_keypair(&path, &generate_keypair(KeyType::Ed25519));
+        let contents = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(result.unwrap_err().kind(), io::ErrorKind::AlreadyExists);
+        assert_eq!(contents, b"existing");
+    }
+
+    // Fixtures were generated with OpenSSL 3, see the commands below. The
+    // go-libp2p protobuf key contains the secret and public key of
+    // `ed25519.pem`.
+
+    /// `openssl genpkey -algorithm ed25519`
+    #[test]
+    fn read_openssl_pkcs8_pem() {
+        let expected: PeerId = "12D3KooWC5RLAUB4nugbfRF1Z4AaieMZCphoVsVd2N9CcDog4mTn"
+            .parse()
+            .unwrap();
+        assert_eq!(peer_id(&fixture("ed25519.pem")), expected);
+    }
+
+    #[test]
+    fn read_go_libp2p_protobuf() {
+        assert_eq!(
+            peer_id(&fixture("ed25519.key")),
+            peer_id(&fixture("ed25519.pem"))
+        );
+    }
+
+    /// `openssl ecparam -name <curve> -genkey`, converted via `openssl pkcs8
+    /// -topk8 -nocrypt`.
+    #[test]
+    fn read_openssl_sec1_pem() {
+        let ecdsa = read_keypair(&fixture("ecdsa-sec1.pem")).unwrap();
+        assert!(matches!(ecdsa, Keypair::Ecdsa(_)));
+        assert_eq!(
+            PeerId::from(ecdsa.public()),
+            peer_id(&fixture("ecdsa-pkcs8.pem"))
+        );
+
+        let secp256k1 = read_keypair(&fixture("secp256k1-sec1.pem")).unwrap();
+        assert!(matches!(secp256k1, Keypair::Secp256k1(_)));
+        assert_eq!(
+            PeerId::from(secp256k1.public()),
+            peer_id(&fixture("secp256k1-pkcs8.pem"))
+        );
+    }
+
+    /// `openssl genpkey -algorithm RSA`, converted via `openssl pkcs8 -topk8
+    /// -nocrypt -outform DER`.
+    #[test]
+    fn read_rsa_pkcs8_der() {
+        let rsa = read_keypair(&fixture("rsa-pkcs8.der")).unwrap();
+        assert!(matches!(rsa, Keypair::Rsa(_)));
+        assert_eq!(
+            PeerId::from(rsa.public()),
+            peer_id(&fixture("rsa-pkcs8.pem"))
+        );
+    }
+
+    #[test]
+    fn reject_garbage() {
+        let path = temp_path();
+        std::fs::write(&path, b"not a key").unwrap();
+        let result = read_keypair(&path);
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(result.unwrap_err().kind(), io::ErrorKind::InvalidData);
+    }
+}
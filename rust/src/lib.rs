@@ -1,9 +1,36 @@
 mod behaviour;
+mod clock;
+mod emulator;
+mod fault;
 mod handler;
+mod key;
+mod limit;
+mod metrics;
+mod payload;
 mod protocol;
+mod report;
+mod resource;
+mod stats;
+mod timing;
 
-pub use behaviour::{Perf, PerfEvent};
+pub use behaviour::{Perf, PerfConfig, PerfEvent, RunId, RunParams, RunResult};
+pub use clock::{Clock, SystemClock, VirtualClock};
+use emulator::Emulated;
+pub use emulator::{EmulatorConfig, EmulatorOpts};
+use fault::Faulty;
+pub use fault::{Fault, FaultConfig, FaultTrigger};
 use futures::executor::block_on;
+pub use handler::{Role, RunStats};
+pub use key::{generate_keypair, read_keypair, write_keypair, KeyType};
+pub use limit::RejectReason;
+pub use metrics::Metrics;
+pub use payload::Payload;
+pub use report::{Report, RunRecord};
+#[cfg(feature = "count-allocations")]
+pub use resource::CountingAllocator;
+pub use resource::{AllocationStats, CpuUsage, MemoryUsage, ResourceUsage};
+pub use stats::{Comparison, Summary};
+pub use timing::{DurationHistogram, WriteTiming};
 
 use libp2p::{
     core::{
@@ -17,8 +44,10 @@ use libp2p::{
     plaintext::PlainText2Config,
     tcp, yamux, PeerId,
 };
+use std::sync::Arc;
+use structopt::StructOpt;
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum TransportSecurity {
     Noise,
     Plaintext,
@@ -44,58 +73,150 @@ impl std::fmt::Display for TransportSecurity {
     }
 }
 
+/// Tunables of the transport stack built by [`build_transport`].
+#[derive(Debug, Clone)]
+pub struct TransportConfig {
+    /// Yamux receive window per stream in bytes.
+    pub yamux_receive_window_size: u32,
+    /// Maximum number of bytes yamux buffers per stream before the stream is
+    /// considered faulty.
+    pub yamux_max_buffer_size: usize,
+    /// Disable Nagle's algorithm on TCP sockets (`TCP_NODELAY`).
+    pub tcp_nodelay: bool,
+    /// IP time-to-live of TCP sockets. Operating system default if `None`.
+    pub tcp_ttl: Option<u32>,
+    /// Reuse the listening port for outgoing TCP connections.
+    pub tcp_port_reuse: bool,
+    /// Kernel send buffer size of TCP sockets (`SO_SNDBUF`). Operating system
+    /// default if `None`.
+    pub tcp_send_buffer_size: Option<usize>,
+    /// Kernel receive buffer size of TCP sockets (`SO_RCVBUF`). Operating
+    /// system default if `None`.
+    pub tcp_recv_buffer_size: Option<usize>,
+    /// Emulate a network link with the given properties on each connection.
+    pub emulation: Option<EmulatorConfig>,
+    /// Inject the given fault on each connection.
+    pub fault: Option<FaultConfig>,
+    /// Clock timing the emulated link and time-based faults.
+    pub clock: Arc<dyn Clock>,
+    /// Count established connections by security protocol.
+    pub metrics: Option<Metrics>,
+}
+
+impl Default for TransportConfig {
+    fn default() -> Self {
+        // The default TCP receive window (minimum, default, maximum) on my OS
+        // (Debian) is:
+        //
+        // $ cat /proc/sys/net/ipv4/tcp_rmem
+        // 4096    131072  6291456
+        //
+        // Possible Bandwidth of a connection ignoring all overheads of TCP would be
+        // calculated with:
+        //
+        // Bandwidth (mBit/s) = (Receive window in bit) / (latency in s) / 1_000_000
+        //
+        // Ping latency via `localhost` is around 0.09 ms:
+        //
+        // $ ping localhost
+        // 64 bytes from localhost (::1): icmp_seq=2 ttl=64 time=0.095 ms
+        // 64 bytes from localhost (::1): icmp_seq=3 ttl=64 time=0.087 ms
+        //
+        // Thus the bandwidth with the maximum receive window would be:
+        //
+        // ((6291456*8) / (0,09/1000)) / 1000000 = 559_240 mBit/s
+        //
+        // An iperf run on localhost achieves around 60 gBit/sec:
+        //
+        // $ iperf -c 127.0.0.1
+        // [  3]  0.0-10.0 sec  68.4 GBytes  58.8 Gbits/sec
+        //
+        // A libp2p-perf run with the default yamux receive window settings (256
+        // kByte) achieves a bandwidth of 30 mBit/s:
+        //
+        // $ cargo run --bin client --release -- --server-address /ip4/127.0.0.1/tcp/9992
+        // Interval        Transfer        Bandwidth
+        // 0 s - 10.08 s   35 MBytes       27.78 MBit/s
+        //
+        // With the yamux receive window set to the OS max receive window (6291456
+        // bytes) libp2p-perf runs as fast as 500 mBit/s:
+        //
+        // $ cargo run --bin client --release -- --server-address /ip4/127.0.0.1/tcp/9992
+        // Interval        Transfer        Bandwidth
+        // 0 s - 10.00 s   614 MBytes      491.19 MBit/s
+        //
+        // Set to golang default of 16MiB
+        // (https://github.com/libp2p/go-libp2p-yamux/blob/35d571287404f972dc626e2de2980ef2c8178b26/transport.go#L15).
+        TransportConfig {
+            yamux_receive_window_size: 16 * 1024 * 1024,
+            yamux_max_buffer_size: 16 * 1024 * 1024,
+            tcp_nodelay: true,
+            tcp_ttl: None,
+            tcp_port_reuse: false,
+            tcp_send_buffer_size: None,
+            tcp_recv_buffer_size: None,
+            emulation: None,
+            fault: None,
+            clock: Arc::new(SystemClock),
+            metrics: None,
+        }
+    }
+}
+
+/// Command line options of the binaries tuning TCP sockets, see
+/// [`TcpOpts::config`].
+#[derive(Debug, Clone, StructOpt)]
+pub struct TcpOpts {
+    /// Enable Nagle's algorithm on TCP sockets.
+    #[structopt(long)]
+    pub tcp_nagle: bool,
+
+    /// IP time-to-live of TCP sockets.
+    #[structopt(long)]
+    pub tcp_ttl: Option<u32>,
+
+    /// Reuse the listening port for outgoing TCP connections.
+    #[structopt(long)]
+    pub tcp_port_reuse: bool,
+
+    /// Kernel send buffer size of TCP sockets (`SO_SNDBUF`) in bytes. Set once
+    /// the connection is established.
+    #[structopt(long)]
+    pub tcp_send_buffer_size: Option<usize>,
+
+    /// Kernel receive buffer size of TCP sockets (`SO_RCVBUF`) in bytes. Set
+    /// once the connection is established, i.e. after the TCP window scale
+    /// was negotiated based on the default buffer size. Thus on Linux, a
+    /// larger size only takes effect up to the maximum of
+    /// `/proc/sys/net/ipv4/tcp_rmem`.
+    #[structopt(long)]
+    pub tcp_recv_buffer_size: Option<usize>,
+}
+
+impl TcpOpts {
+    /// The default [`TransportConfig`] with the given TCP options applied.
+    pub fn config(&self) -> TransportConfig {
+        TransportConfig {
+            tcp_nodelay: !self.tcp_nagle,
+            tcp_ttl: self.tcp_ttl,
+            tcp_port_reuse: self.tcp_port_reuse,
+            tcp_send_buffer_size: self.tcp_send_buffer_size,
+            tcp_recv_buffer_size: self.tcp_recv_buffer_size,
+            ..TransportConfig::default()
+        }
+    }
+}
+
 pub fn build_transport(
     in_memory: bool,
     keypair: identity::Keypair,
     transport_security: TransportSecurity,
+    config: TransportConfig,
 ) -> std::io::Result<core::transport::Boxed<(PeerId, StreamMuxerBox)>> {
     let mut yamux_config = yamux::YamuxConfig::default();
     yamux_config.set_window_update_mode(yamux::WindowUpdateMode::on_read());
-
-    // The default TCP receive window (minimum, default, maximum) on my OS
-    // (Debian) is:
-    //
-    // $ cat /proc/sys/net/ipv4/tcp_rmem
-    // 4096    131072  6291456
-    //
-    // Possible Bandwidth of a connection ignoring all overheads of TCP would be
-    // calculated with:
-    //
-    // Bandwidth (mBit/s) = (Receive window in bit) / (latency in s) / 1_000_000
-    //
-    // Ping latency via `localhost` is around 0.09 ms:
-    //
-    // $ ping localhost
-    // 64 bytes from localhost (::1): icmp_seq=2 ttl=64 time=0.095 ms
-    // 64 bytes from localhost (::1): icmp_seq=3 ttl=64 time=0.087 ms
-    //
-    // Thus the bandwidth with the maximum receive window would be:
-    //
-    // ((6291456*8) / (0,09/1000)) / 1000000 = 559_240 mBit/s
-    //
-    // An iperf run on localhost achieves around 60 gBit/sec:
-    //
-    // $ iperf -c 127.0.0.1
-    // [  3]  0.0-10.0 sec  68.4 GBytes  58.8 Gbits/sec
-    //
-    // A libp2p-perf run with the default yamux receive window settings (256
-    // kByte) achieves a bandwidth of 30 mBit/s:
-    //
-    // $ cargo run --bin client --release -- --server-address /ip4/127.0.0.1/tcp/9992
-    // Interval        Transfer        Bandwidth
-    // 0 s - 10.08 s   35 MBytes       27.78 MBit/s
-    //
-    // With the yamux receive window set to the OS max receive window (6291456
-    // bytes) libp2p-perf runs as fast as 500 mBit/s:
-    //
-    // $ cargo run --bin client --release -- --server-address /ip4/127.0.0.1/tcp/9992
-    // Interval        Transfer        Bandwidth
-    // 0 s - 10.00 s   614 MBytes      491.19 MBit/s
-    //
-    // Set to golang default of 16MiB
-    // (https://github.com/libp2p/go-libp2p-yamux/blob/35d571287404f972dc626e2de2980ef2c8178b26/transport.go#L15).
-    yamux_config.set_receive_window_size(16 * 1024 * 1024);
-    yamux_config.set_max_buffer_size(16 * 1024 * 1024);
+    yamux_config.set_receive_window_size(config.yamux_receive_window_size);
+    yamux_config.set_max_buffer_size(config.yamux_max_buffer_size);
 
     let transport_security_config = match transport_security {
         TransportSecurity::Plaintext => {
@@ -140,107 +261,818 @@ pub fn build_transport(
     let transport = if in_memory {
         EitherTransport::Left(MemoryTransport::new())
     } else {
-        EitherTransport::Right(block_on(dns::DnsConfig::system(
-            tcp::async_io::Transport::new(tcp::Config::new().nodelay(true)),
-        ))?)
+        let mut tcp_config = tcp::Config::new()
+            .nodelay(config.tcp_nodelay)
+            .port_reuse(config.tcp_port_reuse);
+        if let Some(ttl) = config.tcp_ttl {
+            tcp_config = tcp_config.ttl(ttl);
+        }
+
+        let send_buffer_size = config.tcp_send_buffer_size;
+        let recv_buffer_size = config.tcp_recv_buffer_size;
+        // `libp2p-tcp` does not expose the socket before connecting, thus the
+        // buffer sizes are set on the established connection. Note that on
+        // Linux the TCP window scale is negotiated during the handshake based
+        // on the receive buffer at that time, bounded by
+        // `/proc/sys/net/ipv4/tcp_rmem`.
+        let tcp = tcp::async_io::Transport::new(tcp_config).map(move |stream, _| {
+            set_buffer_sizes(
+                &socket2::SockRef::from(stream.get_ref()),
+                send_buffer_size,
+                recv_buffer_size,
+            );
+            stream
+        });
+
+        EitherTransport::Right(block_on(dns::DnsConfig::system(tcp))?)
+    };
+
+    let emulation_clock = config.clock.clone();
+    let transport = match config.emulation {
+        Some(emulation) => EitherTransport::Left(transport.map(move |connection, _| {
+            Emulated::new(connection, emulation.clone(), emulation_clock.clone())
+        })),
+        None => EitherTransport::Right(transport),
     };
 
+    let fault_clock = config.clock.clone();
+    let transport =
+        match config.fault {
+            Some(fault) => EitherTransport::Left(transport.map(move |connection, _| {
+                Faulty::new(connection, fault.clone(), fault_clock.clone())
+            })),
+            None => EitherTransport::Right(transport),
+        };
+
+    let inbound_metrics = config.metrics.clone();
+    let outbound_metrics = config.metrics;
+
     Ok(transport
         .upgrade(core::upgrade::Version::V1)
         .authenticate(
             transport_security_config
-                .map_inbound(move |result| match result {
-                    EitherOutput::First((peer_id, o)) => (peer_id, EitherOutput::First(o)),
-                    EitherOutput::Second((peer_id, o)) => (peer_id, EitherOutput::Second(o)),
-                })
-                .map_outbound(move |result| match result {
-                    EitherOutput::First((peer_id, o)) => (peer_id, EitherOutput::First(o)),
-                    EitherOutput::Second((peer_id, o)) => (peer_id, EitherOutput::Second(o)),
-                }),
+                .map_inbound(move |result| secured(&inbound_metrics, result))
+                .map_outbound(move |result| secured(&outbound_metrics, result)),
         )
         .multiplex(yamux_config)
         .map(|(peer, muxer), _| (peer, StreamMuxerBox::new(muxer)))
         .boxed())
 }
 
+/// Flattens the output of the security upgrade, recording the negotiated
+/// protocol.
+fn secured<A, B>(
+    metrics: &Option<Metrics>,
+    output: EitherOutput<(PeerId, A), (PeerId, B)>,
+) -> (PeerId, EitherOutput<A, B>) {
+    let (security, output) = match output {
+        EitherOutput::First((peer_id, o)) => {
+            (TransportSecurity::Noise, (peer_id, EitherOutput::First(o)))
+        }
+        EitherOutput::Second((peer_id, o)) => (
+            TransportSecurity::Plaintext,
+            (peer_id, EitherOutput::Second(o)),
+        ),
+    };
+    if let Some(metrics) = metrics {
+        metrics.record_connection(security);
+    }
+    output
+}
+
+fn set_buffer_sizes(
+    socket: &socket2::SockRef<'_>,
+    send_buffer_size: Option<usize>,
+    recv_buffer_size: Option<usize>,
+) {
+    if let Some(size) = send_buffer_size {
+        if let Err(e) = socket.set_send_buffer_size(size) {
+            tracing::warn!(size, error = ?e, "Failed to set TCP send buffer size.");
+        }
+    }
+    if let Some(size) = recv_buffer_size {
+        if let Err(e) = socket.set_recv_buffer_size(size) {
+            tracing::warn!(size, error = ?e, "Failed to set TCP receive buffer size.");
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
-    use futures::executor::LocalPool;
-
-    use futures::prelude::*;
     use futures::stream::StreamExt;
-    use futures::task::Spawn;
+    use futures::task::{waker, ArcWake};
     use libp2p::core::multiaddr::{Multiaddr, Protocol};
     use libp2p::swarm::{Swarm, SwarmEvent};
     use rand::random;
 
+    use std::collections::HashSet;
+    use std::io;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::task::{Context, Poll};
+    use std::thread::{self, Thread};
     use std::time::Duration;
 
+    type Event = SwarmEvent<PerfEvent, io::Error>;
+
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    enum Side {
+        Sender,
+        Receiver,
+    }
+
+    /// Configuration of one of the two nodes of a [`TestNet`].
+    #[derive(Default)]
+    struct Node {
+        transport: TransportConfig,
+        perf: PerfConfig,
+    }
+
+    /// Two swarms on in-memory transports, each listening on a random
+    /// address, sharing a [`VirtualClock`] driven by [`TestNet::run`].
+    struct TestNet {
+        clock: Arc<VirtualClock>,
+        sender: Swarm<Perf>,
+        receiver: Swarm<Perf>,
+        sender_address: Multiaddr,
+        receiver_address: Multiaddr,
+    }
+
+    impl TestNet {
+        fn new(sender: Node, receiver: Node) -> Self {
+            let _ = env_logger::try_init();
+
+            let clock = Arc::new(VirtualClock::new());
+            let mut sender = build_swarm(sender, clock.clone());
+            let mut receiver = build_swarm(receiver, clock.clone());
+            let sender_address = block_on(listen(&mut sender));
+            let receiver_address = block_on(listen(&mut receiver));
+
+            TestNet {
+                clock,
+                sender,
+                receiver,
+                sender_address,
+                receiver_address,
+            }
+        }
+        /// Dials the receiver from the sender, which, by default, starts a
+        /// run once connected.
+        fn dial(&mut self) {
+            self.sender.dial(self.receiver_address.clone()).unwrap();
+        }
+
+        /// Starts a run from the sender to the receiver, dialing it unless
+        /// connected.
+        fn start_run(&mut self, params: RunParams) -> RunId {
+            let receiver_peer_id = *self.receiver.local_peer_id();
+            self.sender
+                .behaviour_mut()
+                .add_address(&receiver_peer_id, self.receiver_address.clone());
+            self.sender
+                .behaviour_mut()
+                .start_run(receiver_peer_id, params)
+        }
+
+        /// Runs until a run failed on both sides, returning the error of the
+        /// sender and of the receiver.
+        fn run_until_failed(&mut self) -> (io::Error, io::Error) {
+            let mut sender_error = None;
+            let mut receiver_error = None;
+            self.run(|side, event| {
+                match (side, event) {
+                    (
+                        Side::Sender,
+                        SwarmEvent::Behaviour(PerfEvent::PerfRunFailed {
+                            id: Some(_), error, ..
+                        }),
+                    ) => sender_error = Some(error),
+                    (
+                        Side::Receiver,
+                        SwarmEvent::Behaviour(PerfEvent::PerfRunFailed {
+                            id: None, error, ..
+                        }),
+                    ) => receiver_error = Some(error),
+                    (_, SwarmEvent::Behaviour(e)) => panic!("Unexpected event {:?}.", e),
+                    _ => {}
+                }
+                (sender_error.is_some() && receiver_error.is_some()).then_some(())
+            });
+            (sender_error.unwrap(), receiver_error.unwrap())
+        }
+
+        /// Polls both swarms, passing their events to `f` until it returns a
+        /// value.
+        ///
+        /// Once both swarms are pending without having woken up, the clock
+        /// is advanced to the earliest pending sleep, thus a run takes no
+        /// real time and its timing only depends on the configured link.
+        fn run<T>(&mut self, mut f: impl FnMut(Side, Event) -> Option<T>) -> T {
+            let wake = Arc::new(Wake {
+                woken: AtomicBool::new(false),
+                thread: thread::current(),
+            });
+            let waker = waker(wake.clone());
+            let mut cx = Context::from_waker(&waker);
+
+            loop {
+                wake.woken.store(false, Ordering::SeqCst);
+                let mut progressed = false;
+                for (side, swarm) in [
+                    (Side::Sender, &mut self.sender),
+                    (Side::Receiver, &mut self.receiver),
+                ] {
+                    if let Poll::Ready(event) = swarm.poll_next_unpin(&mut cx) {
+                        progressed = true;
+                        if let Some(value) = f(side, event.expect("Infinite stream.")) {
+                            return value;
+                        }
+                    }
+                }
+                if progressed || wake.woken.load(Ordering::SeqCst) {
+                    continue;
+                }
+
+                if !self.clock.advance_to_next_deadline() {
+                    // Only waiting on real time, e.g. on the timeouts of the
+                    // swarm itself.
+                    while !wake.woken.load(Ordering::SeqCst) {
+                        thread::park();
+                    }
+                }
+            }
+        }
+    }
+
+    /// Waker of [`TestNet::run`], recording whether either swarm was woken.
+    struct Wake {
+        woken: AtomicBool,
+        thread: Thread,
+    }
+
+    impl ArcWake for Wake {
+        fn wake_by_ref(arc_self: &Arc<Self>) {
+            arc_self.woken.store(true, Ordering::SeqCst);
+            arc_self.thread.unpark();
+        }
+    }
+
+    /// Builds a swarm timed by `clock`, by default on an emulated 8 Mbit/s
+    /// link, such that sending data takes virtual time.
+    fn build_swarm(node: Node, clock: Arc<VirtualClock>) -> Swarm<Perf> {
+        let key = identity::Keypair::generate_ed25519();
+        let local_peer_id = PeerId::from(key.public());
+
+        let transport_config = TransportConfig {
+            emulation: node.transport.emulation.or(Some(EmulatorConfig {
+                bandwidth: Some(8_000_000),
+                queue_size: 64 * 1024,
+                ..EmulatorConfig::default()
+            })),
+            clock: clock.clone(),
+            ..node.transport
+        };
+        let transport =
+            build_transport(true, key, TransportSecurity::Plaintext, transport_config).unwrap();
+        let perf = Perf::new(PerfConfig { clock, ..node.perf });
+        // Connections are polled by the swarm itself, thus `TestNet::run`
+        // observes when all of them are pending.
+        Swarm::without_executor(transport, perf, local_peer_id)
+    }
+
+    /// Listens on a random memory address, returning once bound.
+    async fn listen(swarm: &mut Swarm<Perf>) -> Multiaddr {
+        let address: Multiaddr = Protocol::Memory(random::<u64>()).into();
+        let id = swarm.listen_on(address.clone()).unwrap();
+        match swarm.next().await.unwrap() {
+            SwarmEvent::NewListenAddr { listener_id, .. } if listener_id == id => address,
+            _ => panic!("Unexpected event."),
+        }
+    }
+
     #[test]
     fn it_works() {
-        let mut pool = LocalPool::new();
-        let _ = env_logger::try_init();
+        let mut net = TestNet::new(Node::default(), Node::default());
+        net.dial();
 
-        let mut sender = {
-            let key = identity::Keypair::generate_ed25519();
-            let local_peer_id = PeerId::from(key.public());
+        net.run(|side, event| match (side, event) {
+            (Side::Sender, SwarmEvent::Behaviour(PerfEvent::PerfRunDone(result))) => {
+                // Ended by the deadline timer.
+                assert_eq!(result.stats.duration, Duration::from_secs(10));
+                assert_eq!(result.stats.overshoot, Some(Duration::ZERO));
+                assert!(result.sent > 0);
+                assert_eq!(result.role, Role::Sender);
+                assert!(result.stats.write_timing.is_some());
+                Some(())
+            }
+            _ => None,
+        });
+    }
 
-            let transport = build_transport(true, key, TransportSecurity::Plaintext).unwrap();
-            let perf = Perf::default();
-            Swarm::with_async_std_executor(transport, perf, local_peer_id)
-        };
+    #[test]
+    fn connection_reset_fails_run() {
+        let mut net = TestNet::new(
+            Node {
+                transport: TransportConfig {
+                    fault: Some(FaultConfig {
+                        fault: Fault::Reset,
+                        trigger: FaultTrigger::AfterBytes(1_000_000),
+                    }),
+                    ..TransportConfig::default()
+                },
+                ..Node::default()
+            },
+            Node::default(),
+        );
+        net.dial();
 
-        let mut receiver = {
-            let key = identity::Keypair::generate_ed25519();
-            let local_peer_id = PeerId::from(key.public());
+        net.run(|side, event| match (side, event) {
+            (Side::Sender, SwarmEvent::Behaviour(PerfEvent::PerfRunFailed { .. })) => Some(()),
+            (Side::Sender, SwarmEvent::Behaviour(e)) => panic!("Unexpected event {:?}.", e),
+            _ => None,
+        });
+    }
+
+    #[test]
+    fn stalled_run_ends_on_deadline() {
+        // The sender no longer reads window updates, thus its writes stay
+        // pending once the receive window of the receiver is used up, after
+        // about 256 KB, i.e. 0.25 s into the run on the 8 Mbit/s link.
+        let mut net = TestNet::new(
+            Node {
+                transport: TransportConfig {
+                    fault: Some(FaultConfig {
+                        fault: Fault::StallReads,
+                        trigger: FaultTrigger::AfterBytes(100_000),
+                    }),
+                    ..TransportConfig::default()
+                },
+                ..Node::default()
+            },
+            Node {
+                transport: TransportConfig {
+                    yamux_receive_window_size: 256 * 1024,
+                    ..TransportConfig::default()
+                },
+                ..Node::default()
+            },
+        );
+        net.dial();
+
+        net.run(|side, event| match (side, event) {
+            (Side::Sender, SwarmEvent::Behaviour(PerfEvent::PerfRunDone(result))) => {
+                let stats = result.stats;
+                assert_eq!(stats.duration, Duration::from_secs(10));
+                assert_eq!(stats.overshoot, Some(Duration::ZERO));
+                assert!(stats.transfered < 1_000_000);
+                // Stalled for the rest of the run, still stalled at the
+                // deadline.
+                let write_timing = stats.write_timing.unwrap();
+                assert!(write_timing.stalls.max() >= Duration::from_secs(9));
+                Some(())
+            }
+            (Side::Sender, SwarmEvent::Behaviour(e)) => panic!("Unexpected event {:?}.", e),
+            _ => None,
+        });
+    }
+
+    #[test]
+    fn connection_error_fails_run() {
+        let mut net = TestNet::new(
+            Node {
+                transport: TransportConfig {
+                    fault: Some(FaultConfig {
+                        fault: Fault::Error(io::ErrorKind::BrokenPipe),
+                        trigger: FaultTrigger::AfterBytes(1_000_000),
+                    }),
+                    ..TransportConfig::default()
+                },
+                ..Node::default()
+            },
+            Node::default(),
+        );
+        net.dial();
+
+        net.run(|side, event| match (side, event) {
+            (Side::Sender, SwarmEvent::Behaviour(PerfEvent::PerfRunFailed { .. })) => Some(()),
+            (Side::Sender, SwarmEvent::Behaviour(e)) => panic!("Unexpected event {:?}.", e),
+            _ => None,
+        });
+    }
+
+    #[test]
+    fn timed_fault_fails_run() {
+        // Triggered 1 s into the 10 s run.
+        let mut net = TestNet::new(
+            Node {
+                transport: TransportConfig {
+                    fault: Some(FaultConfig {
+                        fault: Fault::Reset,
+                        trigger: FaultTrigger::AfterDuration(Duration::from_secs(1)),
+                    }),
+                    ..TransportConfig::default()
+                },
+                ..Node::default()
+            },
+            Node::default(),
+        );
+        net.dial();
+
+        net.run(|side, event| match (side, event) {
+            (Side::Sender, SwarmEvent::Behaviour(PerfEvent::PerfRunFailed { .. })) => Some(()),
+            (Side::Sender, SwarmEvent::Behaviour(e)) => panic!("Unexpected event {:?}.", e),
+            _ => None,
+        });
+    }
+
+    #[test]
+    fn stalled_receiver_fails_run_on_idle_timeout() {
+        let mut net = TestNet::new(
+            Node::default(),
+            Node {
+                transport: TransportConfig {
+                    fault: Some(FaultConfig {
+                        fault: Fault::StallReads,
+                        trigger: FaultTrigger::AfterBytes(100_000),
+                    }),
+                    ..TransportConfig::default()
+                },
+                perf: PerfConfig {
+                    inbound_idle_timeout: Some(Duration::from_secs(1)),
+                    ..PerfConfig::default()
+                },
+            },
+        );
+        net.dial();
+
+        let (_, receiver_error) = net.run_until_failed();
+        assert_eq!(receiver_error.kind(), io::ErrorKind::TimedOut);
+    }
 
-            let transport = build_transport(true, key, TransportSecurity::Plaintext).unwrap();
-            let perf = Perf::default();
-            Swarm::with_async_std_executor(transport, perf, local_peer_id)
+    #[test]
+    fn idle_connection_is_closed() {
+        let idle = || Node {
+            perf: PerfConfig {
+                run_duration: Duration::from_secs(1),
+                connection_idle_timeout: Duration::from_millis(100),
+                ..PerfConfig::default()
+            },
+            ..Node::default()
         };
-        let receiver_address: Multiaddr = Protocol::Memory(random::<u64>()).into();
-
-        // Wait for receiver to bind to listen address.
-        pool.run_until(async {
-            let id = receiver.listen_on(receiver_address.clone()).unwrap();
-            match receiver.next().await.unwrap() {
-                SwarmEvent::NewListenAddr { listener_id, .. } if listener_id == id => {}
-                _ => panic!("Unexpected event."),
+        let mut net = TestNet::new(idle(), idle());
+        net.dial();
+
+        let mut done = false;
+        net.run(|side, event| match (side, event) {
+            (Side::Sender, SwarmEvent::Behaviour(PerfEvent::PerfRunDone(_))) => {
+                done = true;
+                None
+            }
+            (_, SwarmEvent::ConnectionClosed { .. }) => {
+                assert!(done, "Closed during the run.");
+                Some(())
             }
+            _ => None,
         });
+    }
 
-        pool.spawner()
-            .spawn_obj(
-                async move {
-                    loop {
-                        receiver.next().await;
-                    }
+    #[test]
+    fn start_run_dials_peer() {
+        let mut net = TestNet::new(Node::default(), Node::default());
+        let receiver_peer_id = *net.receiver.local_peer_id();
+
+        let receiver_address = net.receiver_address.clone();
+        net.sender
+            .behaviour_mut()
+            .add_address(&receiver_peer_id, receiver_address);
+        let run_id = net.sender.behaviour_mut().start_run(
+            receiver_peer_id,
+            RunParams {
+                run_duration: Duration::from_secs(1),
+                ..RunParams::default()
+            },
+        );
+
+        net.run(|side, event| match (side, event) {
+            (Side::Sender, SwarmEvent::Behaviour(PerfEvent::PerfRunDone(result))) => {
+                assert_eq!(result.id, Some(run_id));
+                assert_eq!(result.peer_id, receiver_peer_id);
+                assert_eq!(result.stats.duration, Duration::from_secs(1));
+                Some(())
+            }
+            (Side::Sender, SwarmEvent::Behaviour(e)) => panic!("Unexpected event {:?}.", e),
+            _ => None,
+        });
+    }
+
+    #[test]
+    fn inbound_run_limit_rejects_run() {
+        let mut net = TestNet::new(
+            Node::default(),
+            Node {
+                perf: PerfConfig {
+                    max_inbound_runs: Some(0),
+                    ..PerfConfig::default()
+                },
+                ..Node::default()
+            },
+        );
+        net.dial();
+
+        let mut rejected = false;
+        let mut failed = false;
+        net.run(|side, event| {
+            match (side, event) {
+                (
+                    Side::Receiver,
+                    SwarmEvent::Behaviour(PerfEvent::InboundRunRejected { reason, .. }),
+                ) => {
+                    assert_eq!(reason, RejectReason::TooManyRuns);
+                    rejected = true;
                 }
-                .boxed()
-                .into(),
-            )
-            .unwrap();
+                (Side::Sender, SwarmEvent::Behaviour(PerfEvent::PerfRunFailed { .. })) => {
+                    failed = true
+                }
+                (_, SwarmEvent::Behaviour(e)) => panic!("Unexpected event {:?}.", e),
+                _ => {}
+            }
+            (rejected && failed).then_some(())
+        });
+    }
 
-        sender.dial(receiver_address).unwrap();
+    #[test]
+    fn inbound_run_limit_per_peer_rejects_concurrent_run() {
+        let mut net = TestNet::new(
+            Node {
+                perf: PerfConfig {
+                    auto_start: false,
+                    ..PerfConfig::default()
+                },
+                ..Node::default()
+            },
+            Node {
+                perf: PerfConfig {
+                    max_inbound_runs_per_peer: Some(1),
+                    ..PerfConfig::default()
+                },
+                ..Node::default()
+            },
+        );
+        let params = RunParams {
+            run_duration: Duration::from_secs(1),
+            ..RunParams::default()
+        };
+        let runs = [net.start_run(params.clone()), net.start_run(params)];
 
-        pool.run_until(async move {
-            loop {
-                match sender.next().await.unwrap() {
-                    SwarmEvent::Behaviour(PerfEvent::PerfRunDone(duration, _transfered)) => {
-                        if duration < Duration::from_secs(10) {
-                            panic!("Expected test to run at least 10 seconds.")
-                        }
+        let mut rejected = false;
+        let mut done = None;
+        let mut failed = None;
+        net.run(|side, event| {
+            match (side, event) {
+                (
+                    Side::Receiver,
+                    SwarmEvent::Behaviour(PerfEvent::InboundRunRejected { reason, .. }),
+                ) => {
+                    assert_eq!(reason, RejectReason::TooManyRunsFromPeer);
+                    rejected = true;
+                }
+                (Side::Receiver, SwarmEvent::Behaviour(PerfEvent::PerfRunDone(_))) => {}
+                (Side::Sender, SwarmEvent::Behaviour(PerfEvent::PerfRunDone(result))) => {
+                    done = result.id
+                }
+                (Side::Sender, SwarmEvent::Behaviour(PerfEvent::PerfRunFailed { id, .. })) => {
+                    failed = id
+                }
+                (_, SwarmEvent::Behaviour(e)) => panic!("Unexpected event {:?}.", e),
+                _ => {}
+            }
+            (rejected && done.is_some() && failed.is_some()).then_some(())
+        });
 
-                        if duration > Duration::from_secs(11) {
-                            panic!("Expected test to run roughly 10 seconds.")
-                        }
+        assert_ne!(done, failed);
+        assert!(runs.contains(&done.unwrap()));
+        assert!(runs.contains(&failed.unwrap()));
+    }
+
+    #[test]
+    fn inbound_run_limit_frees_slot_of_finished_run() {
+        let mut net = TestNet::new(
+            Node {
+                perf: PerfConfig {
+                    auto_start: false,
+                    ..PerfConfig::default()
+                },
+                ..Node::default()
+            },
+            Node {
+                perf: PerfConfig {
+                    max_inbound_runs: Some(1),
+                    ..PerfConfig::default()
+                },
+                ..Node::default()
+            },
+        );
+        let params = RunParams {
+            run_duration: Duration::from_secs(1),
+            ..RunParams::default()
+        };
 
-                        break;
+        // The second run only starts once the receiver finished the first.
+        for _ in 0..2 {
+            let run_id = net.start_run(params.clone());
+
+            let mut sender_done = false;
+            let mut receiver_done = false;
+            net.run(|side, event| {
+                match (side, event) {
+                    (Side::Sender, SwarmEvent::Behaviour(PerfEvent::PerfRunDone(result))) => {
+                        assert_eq!(result.id, Some(run_id));
+                        sender_done = true;
+                    }
+                    (Side::Receiver, SwarmEvent::Behaviour(PerfEvent::PerfRunDone(_))) => {
+                        receiver_done = true
                     }
+                    (_, SwarmEvent::Behaviour(e)) => panic!("Unexpected event {:?}.", e),
                     _ => {}
                 }
+                (sender_done && receiver_done).then_some(())
+            });
+        }
+        assert_eq!(net.receiver.behaviour().inbound_runs(), 0);
+    }
+
+    #[test]
+    fn disallowed_inbound_connection_is_closed() {
+        let mut net = TestNet::new(
+            Node::default(),
+            Node {
+                perf: PerfConfig {
+                    allowed_peers: Some(HashSet::new()),
+                    ..PerfConfig::default()
+                },
+                ..Node::default()
+            },
+        );
+        let sender_peer_id = *net.sender.local_peer_id();
+        net.dial();
+
+        let mut not_allowed = false;
+        let mut closed = false;
+        net.run(|side, event| {
+            match (side, event) {
+                (
+                    Side::Receiver,
+                    SwarmEvent::Behaviour(PerfEvent::PeerNotAllowed { peer_id, .. }),
+                ) => {
+                    assert_eq!(peer_id, sender_peer_id);
+                    not_allowed = true;
+                }
+                (Side::Receiver, SwarmEvent::ConnectionClosed { peer_id, .. }) => {
+                    assert_eq!(peer_id, sender_peer_id);
+                    closed = true;
+                }
+                // The run started by the sender might be refused before the
+                // connection is closed.
+                (
+                    Side::Receiver,
+                    SwarmEvent::Behaviour(PerfEvent::InboundRunRejected { reason, .. }),
+                ) => {
+                    assert_eq!(reason, RejectReason::NotAllowed)
+                }
+                (Side::Sender, SwarmEvent::Behaviour(PerfEvent::PerfRunFailed { .. })) => {}
+                (_, SwarmEvent::Behaviour(e)) => panic!("Unexpected event {:?}.", e),
+                _ => {}
             }
+            (not_allowed && closed).then_some(())
         });
     }
+
+    #[test]
+    fn disallowed_peer_substream_is_reset() {
+        let mut net = TestNet::new(
+            Node {
+                perf: PerfConfig {
+                    auto_start: false,
+                    ..PerfConfig::default()
+                },
+                ..Node::default()
+            },
+            Node {
+                perf: PerfConfig {
+                    allowed_peers: Some(HashSet::new()),
+                    passive: true,
+                    ..PerfConfig::default()
+                },
+                ..Node::default()
+            },
+        );
+        let receiver_peer_id = *net.receiver.local_peer_id();
+
+        // Outbound connections to peers not allowed are kept, but the peer may
+        // not start perf runs on them.
+        let sender_address = net.sender_address.clone();
+        net.receiver.dial(sender_address).unwrap();
+        net.run(|side, event| match (side, event) {
+            (Side::Sender, SwarmEvent::ConnectionEstablished { .. }) => Some(()),
+            (_, SwarmEvent::Behaviour(e)) => panic!("Unexpected event {:?}.", e),
+            _ => None,
+        });
+        let run_id = net
+            .sender
+            .behaviour_mut()
+            .start_run(receiver_peer_id, RunParams::default());
+
+        let mut rejected = false;
+        let mut failed = false;
+        net.run(|side, event| {
+            match (side, event) {
+                (
+                    Side::Receiver,
+                    SwarmEvent::Behaviour(PerfEvent::InboundRunRejected { reason, .. }),
+                ) => {
+                    assert_eq!(reason, RejectReason::NotAllowed);
+                    rejected = true;
+                }
+                (Side::Sender, SwarmEvent::Behaviour(PerfEvent::PerfRunFailed { id, .. })) => {
+                    assert_eq!(id, Some(run_id));
+                    failed = true;
+                }
+                (_, SwarmEvent::Behaviour(e)) => panic!("Unexpected event {:?}.", e),
+                (_, SwarmEvent::ConnectionClosed { .. }) => panic!("Unexpected connection close."),
+                _ => {}
+            }
+            (rejected && failed).then_some(())
+        });
+    }
+
+    #[test]
+    fn inbound_byte_cap_fails_run_on_both_sides() {
+        let mut net = TestNet::new(
+            Node::default(),
+            Node {
+                perf: PerfConfig {
+                    max_inbound_run_bytes: Some(100_000),
+                    ..PerfConfig::default()
+                },
+                ..Node::default()
+            },
+        );
+        net.dial();
+
+        let (sender_error, receiver_error) = net.run_until_failed();
+        assert_eq!(receiver_error.kind(), io::ErrorKind::Other);
+        assert_eq!(sender_error.kind(), io::ErrorKind::Other);
+        assert!(sender_error.to_string().contains("maximum number of bytes"));
+    }
+
+    #[test]
+    fn inbound_duration_cap_fails_run_on_both_sides() {
+        let mut net = TestNet::new(
+            Node::default(),
+            Node {
+                perf: PerfConfig {
+                    max_inbound_run_duration: Some(Duration::from_secs(1)),
+                    ..PerfConfig::default()
+                },
+                ..Node::default()
+            },
+        );
+        net.dial();
+
+        let (sender_error, receiver_error) = net.run_until_failed();
+        assert_eq!(receiver_error.kind(), io::ErrorKind::TimedOut);
+        assert_eq!(sender_error.kind(), io::ErrorKind::TimedOut);
+        assert!(sender_error.to_string().contains("maximum run duration"));
+    }
+
+    #[test]
+    fn inbound_idle_timeout_fails_run_on_both_sides() {
+        // The receiver stops getting data, while the sender can keep writing
+        // until running out of window.
+        let mut net = TestNet::new(
+            Node {
+                transport: TransportConfig {
+                    fault: Some(FaultConfig {
+                        fault: Fault::TruncateWrites,
+                        trigger: FaultTrigger::AfterBytes(100_000),
+                    }),
+                    ..TransportConfig::default()
+                },
+                ..Node::default()
+            },
+            Node {
+                perf: PerfConfig {
+                    inbound_idle_timeout: Some(Duration::from_secs(1)),
+                    ..PerfConfig::default()
+                },
+                ..Node::default()
+            },
+        );
+        net.dial();
+
+        let (sender_error, receiver_error) = net.run_until_failed();
+        assert_eq!(receiver_error.kind(), io::ErrorKind::TimedOut);
+        assert_eq!(sender_error.kind(), io::ErrorKind::TimedOut);
+        assert!(sender_error.to_string().contains("idle"));
+    }
 }
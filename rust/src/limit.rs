@@ -0,0 +1,130 @@
+//! Limits on the number of concurrent perf runs started by remote peers.
+
+use libp2p::PeerId;
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+
+/// Reason for refusing a perf run started by a remote peer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RejectReason {
+    /// [`PerfConfig::max_inbound_runs`](crate::PerfConfig::max_inbound_runs)
+    /// is reached.
+    TooManyRuns,
+    /// [`PerfConfig::max_inbound_runs_per_peer`](crate::PerfConfig::max_inbound_runs_per_peer)
+    /// is reached.
+    TooManyRunsFromPeer,
+    /// The peer is not in
+    /// [`PerfConfig::allowed_peers`](crate::PerfConfig::allowed_peers).
+    NotAllowed,
+}
+
+impl fmt::Display for RejectReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RejectReason::TooManyRuns => write!(f, "Too many concurrent perf runs."),
+            RejectReason::TooManyRunsFromPeer => {
+                write!(f, "Too many concurrent perf runs from peer.")
+            }
+            RejectReason::NotAllowed => write!(f, "Peer not allowed to start perf runs."),
+        }
+    }
+}
+
+/// Counts perf runs across all connections of a [`Perf`](crate::Perf)
+/// behaviour.
+#[derive(Debug, Default)]
+pub struct RunLimiter {
+    max_runs: Option<usize>,
+    max_runs_per_peer: Option<usize>,
+    state: Mutex<RunLimiterState>,
+}
+
+#[derive(Debug, Default)]
+struct RunLimiterState {
+    runs: usize,
+    runs_per_peer: HashMap<PeerId, usize>,
+}
+
+impl RunLimiter {
+    pub fn new(max_runs: Option<usize>, max_runs_per_peer: Option<usize>) -> Self {
+        RunLimiter {
+            max_runs,
+            max_runs_per_peer,
+            ..Default::default()
+        }
+    }
+
+    /// Number of runs currently holding a [`RunPermit`].
+    pub fn runs(&self) -> usize {
+        self.state.lock().unwrap().runs
+    }
+
+    /// Reserves a slot for a new run from the given peer. The slot is freed
+    /// once the returned [`RunPermit`] is dropped.
+    pub fn try_acquire(self: &Arc<Self>, peer_id: PeerId) -> Result<RunPermit, RejectReason> {
+        let mut state = self.state.lock().unwrap();
+
+        if matches!(self.max_runs, Some(max) if state.runs >= max) {
+            return Err(RejectReason::TooManyRuns);
+        }
+        let peer_runs = state.runs_per_peer.get(&peer_id).copied().unwrap_or(0);
+        if matches!(self.max_runs_per_peer, Some(max) if peer_runs >= max) {
+            return Err(RejectReason::TooManyRunsFromPeer);
+        }
+
+        state.runs += 1;
+        state.runs_per_peer.insert(peer_id, peer_runs + 1);
+
+        Ok(RunPermit {
+            limiter: self.clone(),
+            peer_id,
+        })
+    }
+}
+
+pub struct RunPermit {
+    limiter: Arc<RunLimiter>,
+    peer_id: PeerId,
+}
+
+impl Drop for RunPermit {
+    fn drop(&mut self) {
+        let mut state = self.limiter.state.lock().unwrap();
+
+        state.runs -= 1;
+        if let Some(runs) = state.runs_per_peer.get_mut(&self.peer_id) {
+            *runs -= 1;
+            if *runs == 0 {
+                state.runs_per_peer.remove(&self.peer_id);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn permit_frees_slot_on_drop() {
+        let limiter = Arc::new(RunLimiter::new(Some(2), Some(1)));
+        let peer = PeerId::random();
+
+        let permit = limiter.try_acquire(peer).unwrap();
+        assert_eq!(
+            limiter.try_acquire(peer).err(),
+            Some(RejectReason::TooManyRunsFromPeer)
+        );
+        let _other = limiter.try_acquire(PeerId::random()).unwrap();
+        assert_eq!(
+            limiter.try_acquire(PeerId::random()).err(),
+            Some(RejectReason::TooManyRuns)
+        );
+        assert_eq!(limiter.runs(), 2);
+
+        drop(permit);
+        assert_eq!(limiter.runs(), 1);
+        assert!(limiter.try_acquire(peer).is_ok());
+    }
+}
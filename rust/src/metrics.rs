@@ -0,0 +1,218 @@
+//! Prometheus metrics of a [`Perf`](crate::Perf) behaviour, e.g. of a long
+//! running server.
+
+use crate::behaviour::{PerfEvent, RunResult};
+use crate::TransportSecurity;
+use prometheus_client::encoding::text::Encode;
+use prometheus_client::metrics::counter::Counter;
+use prometheus_client::metrics::family::Family;
+use prometheus_client::metrics::gauge::Gauge;
+use prometheus_client::metrics::histogram::{exponential_buckets, Histogram};
+use prometheus_client::registry::Registry;
+
+/// Metrics fed from [`PerfEvent`]s, registered under the `perf` prefix.
+///
+/// Pass a clone via [`TransportConfig::metrics`](crate::TransportConfig::metrics)
+/// to count connections by security protocol.
+#[derive(Debug, Clone)]
+pub struct Metrics {
+    active_runs: Gauge,
+    bytes_received: Counter,
+    bytes_sent: Counter,
+    run_duration: Histogram,
+    errors: Family<ErrorLabels, Counter>,
+    connections: Family<ConnectionLabels, Counter>,
+}
+
+#[derive(Debug, Clone, Hash, PartialEq, Eq, Encode)]
+struct ErrorLabels {
+    kind: String,
+}
+
+#[derive(Debug, Clone, Hash, PartialEq, Eq, Encode)]
+struct ConnectionLabels {
+    security: Security,
+}
+
+#[derive(Debug, Clone, Hash, PartialEq, Eq, Encode)]
+enum Security {
+    Noise,
+    Plaintext,
+}
+
+impl Metrics {
+    pub fn new(registry: &mut Registry) -> Self {
+        let sub_registry = registry.sub_registry_with_prefix("perf");
+
+        let active_runs = Gauge::default();
+        sub_registry.register(
+            "active_runs",
+            "Number of perf runs started by remote peers currently in progress",
+            Box::new(active_runs.clone()),
+        );
+
+        let bytes_received = Counter::default();
+        sub_registry.register(
+            "bytes_received",
+            "Number of bytes received in successful perf runs",
+            Box::new(bytes_received.clone()),
+        );
+
+        let bytes_sent = Counter::default();
+        sub_registry.register(
+            "bytes_sent",
+            "Number of bytes sent in successful perf runs",
+            Box::new(bytes_sent.clone()),
+        );
+
+        // 100 ms up to 51.2 s, covering the 10 s default run duration.
+        let run_duration = Histogram::new(exponential_buckets(0.1, 2.0, 10));
+        sub_registry.register(
+            "run_duration_seconds",
+            "Duration of successful perf runs",
+            Box::new(run_duration.clone()),
+        );
+
+        let errors = Family::default();
+        sub_registry.register(
+            "errors",
+            "Number of failed or rejected perf runs by kind",
+            Box::new(errors.clone()),
+        );
+
+        let connections = Family::default();
+        sub_registry.register(
+            "connections",
+            "Number of connections established by security protocol",
+            Box::new(connections.clone()),
+        );
+
+        Metrics {
+            active_runs,
+            bytes_received,
+            bytes_sent,
+            run_duration,
+            errors,
+            connections,
+        }
+    }
+
+    pub fn record(&self, event: &PerfEvent) {
+        match event {
+            PerfEvent::PerfRunDone(result) => {
+                let RunResult {
+                    sent,
+                    received,
+                    stats,
+                    ..
+                } = &**result;
+                self.bytes_sent.inc_by(*sent as u64);
+                self.bytes_received.inc_by(*received as u64);
+                self.run_duration.observe(stats.duration.as_secs_f64());
+            }
+            PerfEvent::PerfRunFailed { error, .. } => {
+                self.errors
+                    .get_or_create(&ErrorLabels {
+                        kind: format!("{:?}", error.kind()),
+                    })
+                    .inc();
+            }
+            PerfEvent::InboundRunRejected { reason, .. } => {
+                self.errors
+                    .get_or_create(&ErrorLabels {
+                        kind: format!("{:?}", reason),
+                    })
+                    .inc();
+            }
+            PerfEvent::PeerNotAllowed { .. } => {}
+        }
+    }
+
+    /// Sets the number of perf runs in progress, as returned by
+    /// [`Perf::inbound_runs`](crate::Perf::inbound_runs).
+    ///
+    /// Runs don't emit an event when starting, thus the gauge is best updated
+    /// right before encoding the registry.
+    pub fn set_active_runs(&self, runs: usize) {
+        self.active_runs.set(runs as u64);
+    }
+
+    pub(crate) fn record_connection(&self, security: TransportSecurity) {
+        let security = match security {
+            TransportSecurity::Noise => Security::Noise,
+            TransportSecurity::Plaintext => Security::Plaintext,
+            TransportSecurity::All => unreachable!("Connections negotiate a single protocol."),
+        };
+        self.connections
+            .get_or_create(&ConnectionLabels { security })
+            .inc();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::handler::{Role, RunStats};
+    use crate::RejectReason;
+    use libp2p::core::connection::ConnectionId;
+    use libp2p::{Multiaddr, PeerId};
+    use prometheus_client::encoding::text::encode;
+    use std::io;
+    use std::time::{Duration, SystemTime};
+
+    #[test]
+    fn record_events() {
+        let mut registry = Registry::default();
+        let metrics = Metrics::new(&mut registry);
+        let peer_id = PeerId::random();
+
+        metrics.record(&PerfEvent::PerfRunDone(Box::new(RunResult {
+            id: None,
+            peer_id,
+            connection: ConnectionId::new(0),
+            remote_address: Multiaddr::empty(),
+            role: Role::Receiver,
+            protocol: "/perf/0.1.0".to_string(),
+            params: None,
+            sent: 0,
+            received: 1000,
+            started_at: SystemTime::now(),
+            finished_at: SystemTime::now(),
+            stats: RunStats {
+                duration: Duration::from_secs(10),
+                transfered: 1000,
+                overshoot: None,
+                write_timing: None,
+            },
+            cpu: None,
+            memory: None,
+        })));
+        metrics.record(&PerfEvent::PerfRunFailed {
+            id: None,
+            peer_id,
+            error: io::ErrorKind::TimedOut.into(),
+        });
+        metrics.record(&PerfEvent::InboundRunRejected {
+            peer_id,
+            connection: ConnectionId::new(0),
+            reason: RejectReason::TooManyRuns,
+        });
+        metrics.record(&PerfEvent::InboundRunRejected {
+            peer_id,
+            connection: ConnectionId::new(0),
+            reason: RejectReason::TooManyRuns,
+        });
+        metrics.set_active_runs(2);
+
+        let mut encoded = Vec::new();
+        encode(&mut encoded, &registry).unwrap();
+        let encoded = String::from_utf8(encoded).unwrap();
+
+        assert!(encoded.contains("perf_active_runs 2\n"));
+        assert!(encoded.contains("perf_bytes_received_total 1000\n"));
+        assert!(encoded.contains("perf_bytes_sent_total 0\n"));
+        assert!(encoded.contains("perf_run_duration_seconds_count 1\n"));
+        assert!(encoded.contains("perf_errors_total{kind=\"TimedOut\"} 1\n"));
+        assert!(encoded.contains("perf_errors_total{kind=\"TooManyRuns\"} 2\n"));
+    }
+}
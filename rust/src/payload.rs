@@ -0,0 +1,214 @@
+//! Data written by the sending side of a perf run.
+
+use rand::rngs::StdRng;
+use rand::{RngCore, SeedableRng};
+use std::fmt;
+use std::io;
+use std::path::Path;
+use std::sync::Arc;
+
+/// Length of the pattern of a [`Payload::Random`], beyond the window of
+/// common compression algorithms.
+const RANDOM_PATTERN_LEN: usize = 4 * 1024 * 1024;
+
+/// Data sent by the sender, see
+/// [`PerfConfig::payload`](crate::PerfConfig::payload).
+///
+/// The payload is generated once per run before the run starts, thus it does
+/// not affect the measured throughput. The whole pattern is sent, repeated
+/// until the run ends.
+#[derive(Clone, Default, PartialEq, Eq)]
+pub enum Payload {
+    #[default]
+    Zeros,
+    /// Pseudo-random bytes, the same for a given seed, repeating every 4 MiB.
+    Random { seed: u64 },
+    /// The given text, repeated.
+    Text(String),
+    /// The given bytes, e.g. the contents of a file, repeated.
+    Bytes(Arc<[u8]>),
+}
+
+impl Payload {
+    /// Reads the payload from the given file. Fails on an empty file.
+    pub fn from_file(path: &Path) -> io::Result<Self> {
+        let bytes = std::fs::read(path)?;
+        if bytes.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Payload file {} is empty.", path.display()),
+            ));
+        }
+        Ok(Payload::Bytes(bytes.into()))
+    }
+
+    /// Bytes sent over and over again. An empty text or byte payload falls
+    /// back to zeros.
+    fn pattern(&self) -> Vec<u8> {
+        match self {
+            Payload::Random { seed } => {
+                let mut pattern = vec![0; RANDOM_PATTERN_LEN];
+                StdRng::seed_from_u64(*seed).fill_bytes(&mut pattern);
+                pattern
+            }
+            Payload::Text(text) if !text.is_empty() => text.as_bytes().to_vec(),
+            Payload::Bytes(bytes) if !bytes.is_empty() => bytes.to_vec(),
+            Payload::Zeros | Payload::Text(_) | Payload::Bytes(_) => vec![0],
+        }
+    }
+}
+
+/// Pattern of a [`Payload`], written in slices of the buffer size at a
+/// rotating offset.
+pub struct PayloadBuffer {
+    /// The pattern, followed by its beginning up to the buffer size, such
+    /// that a slice can start at any offset within the pattern.
+    data: Vec<u8>,
+    pattern_len: usize,
+    buffer_size: usize,
+    offset: usize,
+}
+
+impl PayloadBuffer {
+    pub fn new(payload: &Payload, buffer_size: usize) -> Self {
+        let mut data = payload.pattern();
+        let pattern_len = data.len();
+        let len = pattern_len + buffer_size.saturating_sub(1);
+        while data.len() < len {
+            let n = pattern_len.min(len - data.len());
+            data.extend_from_within(..n);
+        }
+
+        PayloadBuffer {
+            data,
+            pattern_len,
+            buffer_size,
+            offset: 0,
+        }
+    }
+
+    /// Next `buffer_size` bytes to write.
+    pub fn next(&self) -> &[u8] {
+        &self.data[self.offset..self.offset + self.buffer_size]
+    }
+
+    /// Marks `n` bytes of [`PayloadBuffer::next`] as written.
+    pub fn advance(&mut self, n: usize) {
+        self.offset = (self.offset + n) % self.pattern_len;
+    }
+}
+
+impl fmt::Debug for Payload {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Payload::Zeros => write!(f, "Zeros"),
+            Payload::Random { seed } => f.debug_struct("Random").field("seed", seed).finish(),
+            Payload::Text(text) => f.debug_tuple("Text").field(text).finish(),
+            // Not printing the possibly large file contents.
+            Payload::Bytes(bytes) => write!(f, "Bytes({} bytes)", bytes.len()),
+        }
+    }
+}
+
+/// Parses `zeros`, `random`, `random:<seed>`, `text:<text>` or `file:<path>`.
+/// The latter reads the file right away.
+impl std::str::FromStr for Payload {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (kind, value) = match s.split_once(':') {
+            Some((kind, value)) => (kind, Some(value)),
+            None => (s, None),
+        };
+
+        match (kind, value) {
+            ("zeros", None) => Ok(Payload::Zeros),
+            ("random", None) => Ok(Payload::Random { seed: 0 }),
+            ("random", Some(seed)) => seed
+                .parse()
+                .map(|seed| Payload::Random { seed })
+                .map_err(|e| format!("Invalid seed {:?}: {}", seed, e)),
+            ("text", Some(text)) if !text.is_empty() => Ok(Payload::Text(text.to_string())),
+            ("file", Some(path)) => {
+                Payload::from_file(Path::new(path)).map_err(|e| format!("{}: {}", path, e))
+            }
+            _ => Err(
+                "Expected one of 'zeros', 'random[:<seed>]', 'text:<text>' or 'file:<path>'."
+                    .to_string(),
+            ),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Writes `len` bytes of the payload in steps of at most `step` bytes.
+    fn write(buffer: &mut PayloadBuffer, len: usize, step: usize) -> Vec<u8> {
+        let mut written = Vec::new();
+        while written.len() < len {
+            let n = step.min(len - written.len());
+            written.extend_from_slice(&buffer.next()[..n]);
+            buffer.advance(n);
+        }
+        written
+    }
+
+    #[test]
+    fn payload_buffers() {
+        let mut zeros = PayloadBuffer::new(&Payload::Zeros, 3);
+        assert_eq!(zeros.next(), [0, 0, 0]);
+        assert_eq!(write(&mut zeros, 5, 2), vec![0; 5]);
+
+        let mut text = PayloadBuffer::new(&Payload::Text("abc".to_string()), 2);
+        assert_eq!(text.next(), b"ab");
+        assert_eq!(write(&mut text, 7, 2), b"abcabca");
+        // Partial writes continue where they left off.
+        assert_eq!(write(&mut text, 5, 1), b"bcabc");
+
+        // Payloads longer than the buffer are sent in full.
+        let long = "0123456789".repeat(100);
+        let mut text = PayloadBuffer::new(&Payload::Text(long.clone()), 64);
+        assert_eq!(write(&mut text, 2000, 64), long.repeat(2).into_bytes());
+
+        let empty = PayloadBuffer::new(&Payload::Text(String::new()), 2);
+        assert_eq!(empty.next(), [0, 0]);
+
+        let random = write(
+            &mut PayloadBuffer::new(&Payload::Random { seed: 42 }, 1024),
+            4096,
+            1024,
+        );
+        assert_eq!(
+            random,
+            write(
+                &mut PayloadBuffer::new(&Payload::Random { seed: 42 }, 1024),
+                4096,
+                1024
+            )
+        );
+        assert_ne!(
+            random,
+            write(
+                &mut PayloadBuffer::new(&Payload::Random { seed: 43 }, 1024),
+                4096,
+                1024
+            )
+        );
+        assert!(random.iter().any(|b| *b != 0));
+        // Not repeating the buffer.
+        assert_ne!(random[..1024], random[1024..2048]);
+
+        assert_eq!(
+            "random:7".parse::<Payload>(),
+            Ok(Payload::Random { seed: 7 })
+        );
+        assert_eq!(
+            "text:abc".parse::<Payload>(),
+            Ok(Payload::Text("abc".to_string()))
+        );
+        assert!("text:".parse::<Payload>().is_err());
+        assert!("ones".parse::<Payload>().is_err());
+    }
+}
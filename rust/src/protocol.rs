@@ -5,6 +5,64 @@ use std::{borrow::Cow, iter};
 
 const PROTOCOL_NAME: &[u8] = b"/perf/0.1.0";
 
+/// Reason for the receiver to abort a perf run started by the remote, sent to
+/// the sender as a single byte before closing the substream.
+///
+/// The receiver never writes otherwise, thus senders not reading the
+/// substream, e.g. older versions or other implementations, are unaffected.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AbortReason {
+    MaxDuration,
+    MaxBytes,
+    IdleTimeout,
+}
+
+impl AbortReason {
+    pub fn to_byte(self) -> u8 {
+        match self {
+            AbortReason::MaxDuration => 1,
+            AbortReason::MaxBytes => 2,
+            AbortReason::IdleTimeout => 3,
+        }
+    }
+
+    pub fn from_byte(byte: u8) -> Option<Self> {
+        match byte {
+            1 => Some(AbortReason::MaxDuration),
+            2 => Some(AbortReason::MaxBytes),
+            3 => Some(AbortReason::IdleTimeout),
+            _ => None,
+        }
+    }
+
+    /// Error failing the run on the sending side.
+    pub fn sender_error(self) -> io::Error {
+        let error = io::Error::from(self);
+        io::Error::new(
+            error.kind(),
+            format!("Receiver aborted the perf run: {}", error),
+        )
+    }
+}
+
+/// Error failing the run on the receiving side.
+impl From<AbortReason> for io::Error {
+    fn from(reason: AbortReason) -> Self {
+        match reason {
+            AbortReason::MaxDuration => io::Error::new(
+                io::ErrorKind::TimedOut,
+                "Perf run exceeded the maximum run duration.",
+            ),
+            AbortReason::MaxBytes => {
+                io::Error::other("Perf run exceeded the maximum number of bytes.")
+            }
+            AbortReason::IdleTimeout => {
+                io::Error::new(io::ErrorKind::TimedOut, "Perf substream idle for too long.")
+            }
+        }
+    }
+}
+
 pub struct PerfProtocolConfig {}
 
 impl UpgradeInfo for PerfProtocolConfig {
@@ -20,12 +78,13 @@ impl<C> InboundUpgrade<C> for PerfProtocolConfig
 where
     C: AsyncRead + AsyncWrite + Unpin,
 {
-    type Output = C;
+    /// The substream along with the negotiated protocol name.
+    type Output = (C, String);
     type Future = future::Ready<Result<Self::Output, io::Error>>;
     type Error = io::Error;
 
-    fn upgrade_inbound(self, incoming: C, _: Self::Info) -> Self::Future {
-        future::ok(incoming)
+    fn upgrade_inbound(self, incoming: C, info: Self::Info) -> Self::Future {
+        future::ok((incoming, String::from_utf8_lossy(&info).into_owned()))
     }
 }
 
@@ -33,11 +92,12 @@ impl<C> OutboundUpgrade<C> for PerfProtocolConfig
 where
     C: AsyncRead + AsyncWrite + Unpin,
 {
-    type Output = C;
+    /// The substream along with the negotiated protocol name.
+    type Output = (C, String);
     type Future = future::Ready<Result<Self::Output, io::Error>>;
     type Error = io::Error;
 
-    fn upgrade_outbound(self, incoming: C, _: Self::Info) -> Self::Future {
-        future::ok(incoming)
+    fn upgrade_outbound(self, incoming: C, info: Self::Info) -> Self::Future {
+        future::ok((incoming, String::from_utf8_lossy(&info).into_owned()))
     }
 }
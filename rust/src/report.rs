@@ -0,0 +1,101 @@
+//! JSON encoded results of a series of perf runs, e.g. to compare against a
+//! baseline with the `compare` binary.
+
+use crate::behaviour::RunResult;
+use serde::{Deserialize, Serialize};
+use std::fs::File;
+use std::io;
+use std::path::Path;
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Report {
+    pub runs: Vec<RunRecord>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RunRecord {
+    /// Authenticated peer ID of the remote.
+    #[serde(default)]
+    pub peer_id: Option<String>,
+    #[serde(default)]
+    pub remote_address: Option<String>,
+    pub duration_secs: f64,
+    pub bytes: usize,
+    /// Throughput in bit/s.
+    pub throughput_bps: f64,
+    /// See [`RunStats::overshoot`](crate::RunStats::overshoot).
+    #[serde(default)]
+    pub overshoot_secs: Option<f64>,
+    /// See [`CpuUsage::percent`](crate::CpuUsage::percent).
+    #[serde(default)]
+    pub cpu_percent: Option<f64>,
+    #[serde(default)]
+    pub bytes_per_cpu_second: Option<f64>,
+    /// See [`MemoryUsage::process_peak_rss`](crate::MemoryUsage::process_peak_rss).
+    #[serde(default)]
+    pub process_peak_rss_bytes: Option<u64>,
+    #[serde(default)]
+    pub allocations: Option<u64>,
+    #[serde(default)]
+    pub allocated_bytes: Option<u64>,
+    /// Total time the sender's writes were pending, see
+    /// [`WriteTiming::stalls`](crate::WriteTiming::stalls).
+    #[serde(default)]
+    pub write_stall_secs: Option<f64>,
+    #[serde(default)]
+    pub write_stall_p99_secs: Option<f64>,
+    #[serde(default)]
+    pub flush_secs: Option<f64>,
+    #[serde(default)]
+    pub close_secs: Option<f64>,
+}
+
+impl From<&RunResult> for RunRecord {
+    fn from(result: &RunResult) -> Self {
+        let write_timing = result.stats.write_timing.as_ref();
+        RunRecord {
+            peer_id: Some(result.peer_id.to_string()),
+            remote_address: Some(result.remote_address.to_string()),
+            duration_secs: result.stats.duration.as_secs_f64(),
+            bytes: result.stats.transfered,
+            throughput_bps: result.stats.throughput(),
+            overshoot_secs: result.stats.overshoot.map(|o| o.as_secs_f64()),
+            cpu_percent: result.cpu.and_then(|cpu| cpu.percent()),
+            bytes_per_cpu_second: result
+                .cpu
+                .and_then(|cpu| cpu.bytes_per_cpu_second(result.stats.transfered)),
+            process_peak_rss_bytes: result.memory.map(|m| m.process_peak_rss),
+            allocations: result.memory.and_then(|m| m.allocations).map(|a| a.count),
+            allocated_bytes: result.memory.and_then(|m| m.allocations).map(|a| a.bytes),
+            write_stall_secs: write_timing.map(|t| t.stalls.total().as_secs_f64()),
+            write_stall_p99_secs: write_timing
+                .and_then(|t| t.stalls.quantile(0.99))
+                .map(|p99| p99.as_secs_f64()),
+            flush_secs: write_timing.map(|t| t.flush.as_secs_f64()),
+            close_secs: write_timing.map(|t| t.close.as_secs_f64()),
+        }
+    }
+}
+
+impl Report {
+    pub fn read(path: &Path) -> io::Result<Self> {
+        let file = File::open(path)?;
+        serde_json::from_reader(io::BufReader::new(file))
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+
+    pub fn write(&self, path: &Path) -> io::Result<()> {
+        let file = File::create(path)?;
+        serde_json::to_writer_pretty(io::BufWriter::new(file), self)?;
+        Ok(())
+    }
+
+    pub fn throughputs(&self) -> Vec<f64> {
+        self.runs.iter().map(|r| r.throughput_bps).collect()
+    }
+
+    /// Overshoots of the runs reporting one.
+    pub fn overshoots(&self) -> Vec<f64> {
+        self.runs.iter().filter_map(|r| r.overshoot_secs).collect()
+    }
+}
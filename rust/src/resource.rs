@@ -0,0 +1,282 @@
+//! Resource usage of the local process during perf runs.
+
+use std::fmt;
+use std::io;
+use std::time::Duration;
+
+/// Snapshot of the resources used by the local process so far.
+#[derive(Debug, Clone, Copy)]
+pub struct ResourceUsage {
+    pub user: Duration,
+    pub system: Duration,
+    /// Peak resident set size of the process since it started, in bytes.
+    pub max_rss: u64,
+    /// Allocations so far, if `CountingAllocator` is in use.
+    pub allocations: Option<AllocationStats>,
+}
+
+impl ResourceUsage {
+    #[cfg(unix)]
+    pub fn now() -> io::Result<Self> {
+        let mut usage = std::mem::MaybeUninit::<libc::rusage>::uninit();
+        // SAFETY: `getrusage` fully initializes `usage` on success.
+        let usage = unsafe {
+            if libc::getrusage(libc::RUSAGE_SELF, usage.as_mut_ptr()) != 0 {
+                return Err(io::Error::last_os_error());
+            }
+            usage.assume_init()
+        };
+
+        // Reported in kilobytes on Linux, in bytes on macOS.
+        let max_rss = if cfg!(target_os = "macos") {
+            usage.ru_maxrss as u64
+        } else {
+            usage.ru_maxrss as u64 * 1024
+        };
+
+        Ok(ResourceUsage {
+            user: timeval_to_duration(usage.ru_utime),
+            system: timeval_to_duration(usage.ru_stime),
+            max_rss,
+            allocations: AllocationStats::now(),
+        })
+    }
+
+    #[cfg(not(unix))]
+    pub fn now() -> io::Result<Self> {
+        Err(io::Error::new(
+            io::ErrorKind::Other,
+            "Resource usage is only supported on unix.",
+        ))
+    }
+
+    /// CPU time used since `earlier`, over `wall` time passed in between.
+    pub fn cpu_since(&self, earlier: &ResourceUsage, wall: Duration) -> CpuUsage {
+        CpuUsage {
+            user: self.user.saturating_sub(earlier.user),
+            system: self.system.saturating_sub(earlier.system),
+            wall,
+        }
+    }
+
+    /// Memory used since `earlier`. Allocations are counted since `earlier`,
+    /// while the peak resident set size is the one of the whole process
+    /// lifetime, as the operating system does not track it per interval.
+    pub fn memory_since(&self, earlier: &ResourceUsage) -> MemoryUsage {
+        MemoryUsage {
+            process_peak_rss: self.max_rss,
+            allocations: self
+                .allocations
+                .zip(earlier.allocations)
+                .map(|(now, earlier)| AllocationStats {
+                    count: now.count - earlier.count,
+                    bytes: now.bytes - earlier.bytes,
+                }),
+        }
+    }
+}
+
+#[cfg(unix)]
+fn timeval_to_duration(time: libc::timeval) -> Duration {
+    Duration::from_secs(time.tv_sec as u64) + Duration::from_micros(time.tv_usec as u64)
+}
+
+/// CPU time used by the local process during a perf run.
+///
+/// Covers the whole process, i.e. includes concurrent runs and any other work
+/// of the process.
+#[derive(Debug, Clone, Copy)]
+pub struct CpuUsage {
+    pub user: Duration,
+    pub system: Duration,
+    /// Wall-clock time of the run.
+    pub wall: Duration,
+}
+
+impl CpuUsage {
+    pub fn total(&self) -> Duration {
+        self.user + self.system
+    }
+
+    /// CPU time relative to the wall-clock time in percent of a single core.
+    /// `None` if no wall-clock time passed.
+    pub fn percent(&self) -> Option<f64> {
+        (!self.wall.is_zero()).then(|| self.total().as_secs_f64() / self.wall.as_secs_f64() * 100.0)
+    }
+
+    /// Bytes transferred per second of CPU time. `None` if no CPU time was
+    /// used, e.g. below the resolution of the operating system's accounting.
+    pub fn bytes_per_cpu_second(&self, bytes: usize) -> Option<f64> {
+        (!self.total().is_zero()).then(|| bytes as f64 / self.total().as_secs_f64())
+    }
+}
+
+/// Memory used by the local process during a perf run.
+///
+/// Like [`CpuUsage`], covers the whole process.
+#[derive(Debug, Clone, Copy)]
+pub struct MemoryUsage {
+    /// Peak resident set size of the process since it started, as of the end
+    /// of the run, in bytes. Not specific to the run, e.g. includes earlier
+    /// runs.
+    pub process_peak_rss: u64,
+    /// Allocations during the run, if `CountingAllocator` is in use.
+    pub allocations: Option<AllocationStats>,
+}
+
+impl fmt::Display for MemoryUsage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Memory: process peak RSS {:.2} MBytes",
+            self.process_peak_rss as f64 / 1000.0 / 1000.0
+        )?;
+        if let Some(allocations) = self.allocations {
+            write!(
+                f,
+                ", {} allocations, {:.2} MBytes allocated",
+                allocations.count,
+                allocations.bytes as f64 / 1000.0 / 1000.0
+            )?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AllocationStats {
+    /// Number of allocations.
+    pub count: u64,
+    /// Number of bytes allocated, not accounting for deallocations.
+    pub bytes: u64,
+}
+
+impl AllocationStats {
+    /// Returns the allocations so far, or `None` if `CountingAllocator` is not
+    /// the global allocator.
+    #[cfg(feature = "count-allocations")]
+    pub fn now() -> Option<Self> {
+        use std::sync::atomic::Ordering;
+
+        if !counting::INSTALLED.load(Ordering::Relaxed) {
+            return None;
+        }
+
+        Some(AllocationStats {
+            count: counting::COUNT.load(Ordering::Relaxed),
+            bytes: counting::BYTES.load(Ordering::Relaxed),
+        })
+    }
+
+    #[cfg(not(feature = "count-allocations"))]
+    pub fn now() -> Option<Self> {
+        None
+    }
+}
+
+#[cfg(feature = "count-allocations")]
+pub use counting::CountingAllocator;
+
+#[cfg(feature = "count-allocations")]
+mod counting {
+    use std::alloc::{GlobalAlloc, Layout, System};
+    use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+
+    pub(super) static INSTALLED: AtomicBool = AtomicBool::new(false);
+    pub(super) static COUNT: AtomicU64 = AtomicU64::new(0);
+    pub(super) static BYTES: AtomicU64 = AtomicU64::new(0);
+
+    /// Global allocator counting allocations, wrapping the [`System`]
+    /// allocator.
+    ///
+    /// ```ignore
+    /// #[global_allocator]
+    /// static ALLOC: libp2p_perf::CountingAllocator = libp2p_perf::CountingAllocator;
+    /// ```
+    pub struct CountingAllocator;
+
+    impl CountingAllocator {
+        fn record(&self, size: usize) {
+            INSTALLED.store(true, Ordering::Relaxed);
+            COUNT.fetch_add(1, Ordering::Relaxed);
+            BYTES.fetch_add(size as u64, Ordering::Relaxed);
+        }
+    }
+
+    unsafe impl GlobalAlloc for CountingAllocator {
+        unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+            self.record(layout.size());
+            System.alloc(layout)
+        }
+
+        unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+            self.record(layout.size());
+            System.alloc_zeroed(layout)
+        }
+
+        unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+            self.record(new_size);
+            System.realloc(ptr, layout, new_size)
+        }
+
+        unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+            System.dealloc(ptr, layout)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn usage(user_ms: u64, system_ms: u64) -> ResourceUsage {
+        ResourceUsage {
+            user: Duration::from_millis(user_ms),
+            system: Duration::from_millis(system_ms),
+            max_rss: 0,
+            allocations: None,
+        }
+    }
+
+    #[test]
+    fn cpu_usage_since() {
+        let cpu = usage(2000, 1000).cpu_since(&usage(1000, 500), Duration::from_secs(3));
+
+        assert_eq!(cpu.user, Duration::from_millis(1000));
+        assert_eq!(cpu.system, Duration::from_millis(500));
+        assert_eq!(cpu.percent(), Some(50.0));
+        assert_eq!(cpu.bytes_per_cpu_second(3000), Some(2000.0));
+    }
+
+    #[test]
+    fn cpu_usage_without_time_passed() {
+        let cpu = usage(1000, 500).cpu_since(&usage(1000, 500), Duration::ZERO);
+
+        assert_eq!(cpu.percent(), None);
+        assert_eq!(cpu.bytes_per_cpu_second(3000), None);
+    }
+
+    #[cfg(feature = "count-allocations")]
+    #[global_allocator]
+    static ALLOC: CountingAllocator = CountingAllocator;
+
+    #[cfg(feature = "count-allocations")]
+    #[test]
+    fn memory_usage_counts_allocations() {
+        let start = ResourceUsage::now().unwrap();
+        let buffer = vec![0u8; 1024 * 1024];
+        let end = ResourceUsage::now().unwrap();
+        drop(buffer);
+
+        let allocations = end.memory_since(&start).allocations.unwrap();
+        assert!(allocations.count >= 1);
+        assert!(allocations.bytes >= 1024 * 1024);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn resource_usage_now() {
+        let usage = ResourceUsage::now().unwrap();
+        assert!(usage.max_rss > 0);
+    }
+}
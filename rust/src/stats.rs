@@ -0,0 +1,194 @@
+//! Summary statistics across repeated perf runs.
+
+/// Summary of a set of samples, e.g. the throughput of repeated perf runs.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Summary {
+    pub count: usize,
+    pub mean: f64,
+    /// Sample standard deviation. Zero for a single sample.
+    pub stddev: f64,
+    pub min: f64,
+    pub max: f64,
+    /// Half-width of the 95 % confidence interval of the mean, based on
+    /// Student's t-distribution. Zero for a single sample.
+    pub ci95: f64,
+}
+
+impl Summary {
+    /// Returns `None` if there are no samples.
+    pub fn new(samples: &[f64]) -> Option<Self> {
+        if samples.is_empty() {
+            return None;
+        }
+
+        let count = samples.len();
+        let mean = samples.iter().sum::<f64>() / count as f64;
+        let stddev = if count > 1 {
+            let variance =
+                samples.iter().map(|s| (s - mean).powi(2)).sum::<f64>() / (count - 1) as f64;
+            variance.sqrt()
+        } else {
+            0.0
+        };
+        let ci95 = if count > 1 {
+            t_975(count - 1) * stddev / (count as f64).sqrt()
+        } else {
+            0.0
+        };
+
+        Some(Summary {
+            count,
+            mean,
+            stddev,
+            min: samples.iter().copied().fold(f64::INFINITY, f64::min),
+            max: samples.iter().copied().fold(f64::NEG_INFINITY, f64::max),
+            ci95,
+        })
+    }
+}
+
+/// 97.5 % quantile of Student's t-distribution with the given degrees of
+/// freedom, i.e. the critical value of a two-sided test at the 5 % level.
+pub(crate) fn t_975(degrees_of_freedom: usize) -> f64 {
+    const TABLE: [f64; 30] = [
+        12.706, 4.303, 3.182, 2.776, 2.571, 2.447, 2.365, 2.306, 2.262, 2.228, 2.201, 2.179, 2.160,
+        2.145, 2.131, 2.120, 2.110, 2.101, 2.093, 2.086, 2.080, 2.074, 2.069, 2.064, 2.060, 2.056,
+        2.052, 2.048, 2.045, 2.042,
+    ];
+
+    match degrees_of_freedom {
+        0 => f64::INFINITY,
+        df @ 1..=30 => TABLE[df - 1],
+        31..=40 => 2.021,
+        41..=60 => 2.000,
+        61..=120 => 1.980,
+        _ => 1.960,
+    }
+}
+
+/// Comparison of a metric between a baseline and a current set of samples.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Comparison {
+    pub baseline: Summary,
+    pub current: Summary,
+    /// Change of the current mean relative to the baseline mean, e.g. `-0.05`
+    /// for a 5 % decrease. `None` if the baseline mean is zero.
+    pub relative_change: Option<f64>,
+    /// Whether the difference of the means is statistically significant at
+    /// the 5 % level according to Welch's t-test. `None` if either side has
+    /// less than two samples.
+    pub significant: Option<bool>,
+}
+
+impl Comparison {
+    /// Returns `None` if either side has no samples.
+    pub fn new(baseline: &[f64], current: &[f64]) -> Option<Self> {
+        let baseline = Summary::new(baseline)?;
+        let current = Summary::new(current)?;
+
+        let relative_change =
+            (baseline.mean != 0.0).then(|| (current.mean - baseline.mean) / baseline.mean);
+
+        let significant = if baseline.count > 1 && current.count > 1 {
+            let var_b = baseline.stddev.powi(2) / baseline.count as f64;
+            let var_c = current.stddev.powi(2) / current.count as f64;
+            let standard_error = (var_b + var_c).sqrt();
+
+            if standard_error == 0.0 {
+                Some(current.mean != baseline.mean)
+            } else {
+                let t = (current.mean - baseline.mean) / standard_error;
+                // Welch–Satterthwaite approximation of the degrees of freedom.
+                let df = (var_b + var_c).powi(2)
+                    / (var_b.powi(2) / (baseline.count - 1) as f64
+                        + var_c.powi(2) / (current.count - 1) as f64);
+                Some(t.abs() > t_975(df.floor() as usize))
+            }
+        } else {
+            None
+        };
+
+        Some(Comparison {
+            baseline,
+            current,
+            relative_change,
+            significant,
+        })
+    }
+
+    /// Whether the mean decreased by more than `threshold`, e.g. `0.05` for
+    /// 5 %, and the decrease is significant. Without enough samples for a
+    /// significance test, relies on the threshold alone.
+    pub fn regressed(&self, threshold: f64) -> bool {
+        matches!(self.relative_change, Some(change) if change < -threshold)
+            && self.significant != Some(false)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn summary_of_samples() {
+        assert_eq!(Summary::new(&[]), None);
+
+        let single = Summary::new(&[3.0]).unwrap();
+        assert_eq!(single.mean, 3.0);
+        assert_eq!(single.stddev, 0.0);
+        assert_eq!(single.ci95, 0.0);
+
+        let summary = Summary::new(&[2.0, 4.0, 4.0, 4.0, 5.0, 5.0, 7.0, 9.0]).unwrap();
+        assert_eq!(summary.count, 8);
+        assert_eq!(summary.mean, 5.0);
+        assert!((summary.stddev - 2.138).abs() < 0.001);
+        assert_eq!(summary.min, 2.0);
+        assert_eq!(summary.max, 9.0);
+        assert!((summary.ci95 - 2.365 * summary.stddev / 8f64.sqrt()).abs() < 1e-9);
+    }
+
+    #[test]
+    fn significant_regression() {
+        let comparison = Comparison::new(
+            &[100.0, 101.0, 99.0, 100.0, 100.0],
+            &[80.0, 81.0, 79.0, 80.0, 80.0],
+        )
+        .unwrap();
+
+        assert!((comparison.relative_change.unwrap() + 0.2).abs() < 1e-9);
+        assert_eq!(comparison.significant, Some(true));
+        assert!(comparison.regressed(0.05));
+        assert!(!comparison.regressed(0.25));
+    }
+
+    #[test]
+    fn non_significant_change() {
+        // A 5 % decrease well within the noise of the samples.
+        let comparison = Comparison::new(
+            &[100.0, 120.0, 80.0, 110.0, 90.0],
+            &[95.0, 115.0, 75.0, 105.0, 85.0],
+        )
+        .unwrap();
+
+        assert!((comparison.relative_change.unwrap() + 0.05).abs() < 1e-9);
+        assert_eq!(comparison.significant, Some(false));
+        assert!(!comparison.regressed(0.01));
+    }
+
+    #[test]
+    fn single_sample_relies_on_threshold() {
+        let comparison = Comparison::new(&[100.0], &[90.0]).unwrap();
+
+        assert_eq!(comparison.significant, None);
+        assert!(comparison.regressed(0.05));
+        assert!(!comparison.regressed(0.15));
+    }
+
+    #[test]
+    fn zero_baseline() {
+        let comparison = Comparison::new(&[0.0, 0.0], &[0.001, 0.002]).unwrap();
+
+        assert_eq!(comparison.relative_change, None);
+        assert!(!comparison.regressed(0.05));
+    }
+}
@@ -0,0 +1,125 @@
+//! Timing of the writes of the sending side of a perf run, e.g. to tell
+//! back-pressure of the muxer apart from a slow socket.
+
+use std::fmt;
+use std::time::Duration;
+
+/// Histogram of durations with power-of-two microsecond buckets.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct DurationHistogram {
+    /// Bucket `i` counts durations below `2^i` µs and, for `i > 0`, at least
+    /// `2^(i-1)` µs.
+    buckets: Vec<u64>,
+    count: u64,
+    total: Duration,
+    max: Duration,
+}
+
+impl DurationHistogram {
+    pub fn record(&mut self, duration: Duration) {
+        let index = (u128::BITS - duration.as_micros().leading_zeros()) as usize;
+        if self.buckets.len() <= index {
+            self.buckets.resize(index + 1, 0);
+        }
+        self.buckets[index] += 1;
+        self.count += 1;
+        self.total += duration;
+        self.max = self.max.max(duration);
+    }
+
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+
+    /// Sum of all recorded durations.
+    pub fn total(&self) -> Duration {
+        self.total
+    }
+
+    pub fn max(&self) -> Duration {
+        self.max
+    }
+
+    /// Upper bound of the given quantile, e.g. `0.99`, or `None` if nothing
+    /// was recorded.
+    pub fn quantile(&self, quantile: f64) -> Option<Duration> {
+        let rank = ((quantile * self.count as f64).ceil() as u64).max(1);
+
+        let mut seen = 0;
+        self.buckets()
+            .find(|(_, count)| {
+                seen += count;
+                seen >= rank
+            })
+            .map(|(upper_bound, _)| upper_bound.min(self.max))
+    }
+
+    /// Exclusive upper bound and count of each bucket.
+    pub fn buckets(&self) -> impl Iterator<Item = (Duration, u64)> + '_ {
+        self.buckets
+            .iter()
+            .enumerate()
+            .map(|(i, count)| (Duration::from_micros(1 << i), *count))
+    }
+}
+
+/// Time the sender spent waiting on the substream.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct WriteTiming {
+    /// Time each `poll_write` stayed pending until accepting data, i.e. until
+    /// the muxer had buffer or window space left. Includes a write still
+    /// pending at the end of the run.
+    pub stalls: DurationHistogram,
+    /// Time to flush the substream at the end of the run.
+    pub flush: Duration,
+    /// Time to close the substream after flushing it.
+    pub close: Duration,
+}
+
+impl fmt::Display for WriteTiming {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Write stalls: {} totaling {:.2} ms",
+            self.stalls.count(),
+            self.stalls.total().as_secs_f64() * 1000.0
+        )?;
+        if let (Some(p50), Some(p99)) = (self.stalls.quantile(0.5), self.stalls.quantile(0.99)) {
+            write!(
+                f,
+                " (p50 <= {:.3} ms, p99 <= {:.3} ms, max {:.3} ms)",
+                p50.as_secs_f64() * 1000.0,
+                p99.as_secs_f64() * 1000.0,
+                self.stalls.max().as_secs_f64() * 1000.0
+            )?;
+        }
+        write!(
+            f,
+            ", flush {:.2} ms, close {:.2} ms",
+            self.flush.as_secs_f64() * 1000.0,
+            self.close.as_secs_f64() * 1000.0
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn duration_histogram_quantiles() {
+        let mut histogram = DurationHistogram::default();
+        assert_eq!(histogram.quantile(0.5), None);
+
+        for micros in [0, 3, 3, 100, 5000] {
+            histogram.record(Duration::from_micros(micros));
+        }
+
+        assert_eq!(histogram.count(), 5);
+        assert_eq!(histogram.total(), Duration::from_micros(5106));
+        // 3 µs falls into the bucket below 4 µs.
+        assert_eq!(histogram.quantile(0.5), Some(Duration::from_micros(4)));
+        // Bounded by the largest recorded duration.
+        assert_eq!(histogram.quantile(1.0), Some(Duration::from_micros(5000)));
+    }
+}
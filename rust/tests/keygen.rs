@@ -0,0 +1,49 @@
+//! Runs the `keygen` binary and loads its output as the identity of the
+//! `server` binary.
+
+use std::io::{BufRead, BufReader};
+use std::path::PathBuf;
+use std::process::{Command, Stdio};
+
+fn temp_path() -> PathBuf {
+    std::env::temp_dir().join(format!("libp2p-perf-keygen-{}", rand::random::<u64>()))
+}
+
+#[test]
+fn server_identity_matches_keygen_peer_id() {
+    for key_type in ["ed25519", "secp256k1", "ecdsa"] {
+        let path = temp_path();
+
+        let output = Command::new(env!("CARGO_BIN_EXE_keygen"))
+            .arg("--output")
+            .arg(&path)
+            .args(["--key-type", key_type])
+            .output()
+            .unwrap();
+        assert!(output.status.success(), "{:?}", output);
+        let peer_id = String::from_utf8(output.stdout).unwrap().trim().to_string();
+
+        let mut server = Command::new(env!("CARGO_BIN_EXE_server"))
+            .arg("--identity")
+            .arg(&path)
+            .args(["--listen-address", "/ip4/127.0.0.1/tcp/0"])
+            .stdout(Stdio::piped())
+            .spawn()
+            .unwrap();
+        let listen_address = BufReader::new(server.stdout.take().unwrap())
+            .lines()
+            .map(Result::unwrap)
+            .find_map(|line| line.strip_prefix("Listening on ").map(str::to_string));
+        server.kill().unwrap();
+        server.wait().unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let listen_address = listen_address.expect("Server to print its listen address.");
+        assert!(
+            listen_address.ends_with(&format!("/p2p/{}.", peer_id)),
+            "{} does not end in {}",
+            listen_address,
+            peer_id
+        );
+    }
+}